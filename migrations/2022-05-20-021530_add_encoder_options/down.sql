@@ -0,0 +1 @@
+ALTER TABLE `object` DROP COLUMN `encoder_options`;
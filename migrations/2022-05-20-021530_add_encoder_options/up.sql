@@ -0,0 +1 @@
+ALTER TABLE `object` ADD COLUMN `encoder_options` text;
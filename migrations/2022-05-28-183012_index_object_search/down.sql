@@ -0,0 +1,3 @@
+DROP INDEX `object_content_type_encoding`;
+DROP INDEX `object_dimensions`;
+DROP INDEX `virtual_object_derived`;
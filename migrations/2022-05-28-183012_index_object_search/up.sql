@@ -0,0 +1,3 @@
+CREATE INDEX `object_content_type_encoding` on `object`(`content_type`, `content_encoding`);
+CREATE INDEX `object_dimensions` on `object`(`width`, `height`);
+CREATE INDEX `virtual_object_derived` on `virtual_object`(`derived_virtual_object_id`);
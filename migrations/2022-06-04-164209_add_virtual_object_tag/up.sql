@@ -0,0 +1,7 @@
+CREATE TABLE `virtual_object_tag` (
+    `virtual_object_id` integer not null,
+    `tag` text not null,
+    primary key(`virtual_object_id`, `tag`),
+    foreign key (`virtual_object_id`) references `virtual_object`(`id`)
+);
+CREATE INDEX `virtual_object_tag_tag` on `virtual_object_tag`(`tag`, `virtual_object_id`);
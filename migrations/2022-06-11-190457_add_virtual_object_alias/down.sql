@@ -0,0 +1,2 @@
+ALTER TABLE `virtual_object` DROP COLUMN `redirect_status`;
+ALTER TABLE `virtual_object` DROP COLUMN `alias_virtual_object_id`;
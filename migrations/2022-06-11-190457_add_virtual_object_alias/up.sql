@@ -0,0 +1,2 @@
+ALTER TABLE `virtual_object` ADD COLUMN `alias_virtual_object_id` integer references `virtual_object`(`id`);
+ALTER TABLE `virtual_object` ADD COLUMN `redirect_status` integer;
@@ -0,0 +1,2 @@
+DROP TABLE `virtual_object_revision_object`;
+DROP TABLE `virtual_object_revision`;
@@ -0,0 +1,19 @@
+CREATE TABLE `virtual_object_revision` (
+    `id` integer primary key autoincrement not null,
+    `virtual_object_id` integer not null,
+    `created` bigint not null,
+    `actor` text,
+    `action` text not null,
+    `primary_object_id` integer,
+    foreign key (`virtual_object_id`) references `virtual_object`(`id`),
+    foreign key (`primary_object_id`) references `object`(`id`)
+);
+CREATE INDEX `virtual_object_revision_virtual_object` on `virtual_object_revision`(`virtual_object_id`, `id`);
+CREATE TABLE `virtual_object_revision_object` (
+    `revision_id` integer not null,
+    `object_id` integer not null,
+    primary key(`revision_id`, `object_id`),
+    foreign key (`revision_id`) references `virtual_object_revision`(`id`),
+    foreign key (`object_id`) references `object`(`id`)
+);
+CREATE INDEX `virtual_object_revision_object_object` on `virtual_object_revision_object`(`object_id`);
@@ -0,0 +1,8 @@
+CREATE TABLE `variant_recipe` (
+    `id` integer primary key autoincrement not null,
+    `path` text not null,
+    `prefix` boolean not null,
+    `recipes` text not null,
+    `modified` bigint not null,
+    unique(`path`, `prefix`)
+);
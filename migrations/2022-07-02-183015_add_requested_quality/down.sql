@@ -0,0 +1 @@
+ALTER TABLE `object` DROP COLUMN `requested_quality`;
@@ -0,0 +1 @@
+ALTER TABLE `object` ADD COLUMN `requested_quality` text;
@@ -0,0 +1,99 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use once_cell::sync::OnceCell;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+
+static ADMIN_TOKEN_HASH: OnceCell<Option<blake3::Hash>> = OnceCell::new();
+
+fn admin_token_hash() -> Option<blake3::Hash> {
+    *ADMIN_TOKEN_HASH.get_or_init(|| match std::env::var("ADMIN_TOKEN") {
+        Ok(token) if !token.is_empty() => Some(blake3::hash(token.as_bytes())),
+        _ => {
+            println!("Warning ADMIN_TOKEN is not set, administrative requests are refused");
+            None
+        }
+    })
+}
+
+// Hashes are compared in constant time, the token length is not revealed either
+pub fn check_admin_token(presented: &str) -> bool {
+    match admin_token_hash() {
+        Some(expected) => blake3::hash(presented.as_bytes()) == expected,
+        None => false,
+    }
+}
+
+static TRUST_PROXY: OnceCell<bool> = OnceCell::new();
+
+// The address header (X-Real-IP unless configured) is only believed behind a proxy that sets it
+fn trust_proxy() -> bool {
+    *TRUST_PROXY.get_or_init(|| {
+        std::env::var("TRUST_PROXY")
+            .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false)
+    })
+}
+
+// Who made a change, recorded with revisions.
+// Without accounts this is "admin" for token holders, otherwise the client address.
+// The forwarded address is used only when TRUST_PROXY is set, a client can send any header.
+pub struct Actor(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Actor {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let is_admin = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| check_admin_token(token.trim()))
+            .unwrap_or(false);
+        if is_admin {
+            Outcome::Success(Actor(Some("admin".to_string())))
+        } else {
+            let ip = if trust_proxy() {
+                req.client_ip()
+            } else {
+                req.remote().map(|remote| remote.ip())
+            };
+            Outcome::Success(Actor(ip.map(|ip| ip.to_string())))
+        }
+    }
+}
+
+// Guards routes that change or remove content, sent as `Authorization: Bearer <token>`
+pub struct AdminToken;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let presented = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match presented {
+            Some(token) if check_admin_token(token.trim()) => Outcome::Success(AdminToken),
+            Some(_) => Outcome::Failure((Status::Forbidden, "Invalid token".to_string())),
+            None => Outcome::Failure((Status::Unauthorized, "Token required".to_string())),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::encoder_options::EncoderOptions;
+use crate::image_operations::{ImageFormat, ImageFrames};
+use std::collections::HashSet;
+
+// JPEG, PNG, and GIF are always accepted, newer formats must be asked for.
+// Browsers list these explicitly, */* is not enough.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptedFormats {
+    pub avif: bool,
+    pub webp: bool,
+    pub jxl: bool,
+}
+
+impl AcceptedFormats {
+    // Used when there is no client to ask, such as the derive API
+    pub fn all() -> Self {
+        Self {
+            avif: true,
+            webp: true,
+            jxl: true,
+        }
+    }
+
+    pub fn from_accept(accept: &str) -> Self {
+        let mut accepted = Self {
+            avif: false,
+            webp: false,
+            jxl: false,
+        };
+        for range in accept.split(',') {
+            let mut parts = range.split(';');
+            let media_type = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality <= 0.0 {
+                continue;
+            }
+            match media_type.as_str() {
+                "image/avif" => accepted.avif = true,
+                "image/webp" => accepted.webp = true,
+                "image/jxl" => accepted.jxl = true,
+                _ => {}
+            }
+        }
+        accepted
+    }
+
+    fn accepts(&self, format: &ImageFormat) -> bool {
+        match format {
+            ImageFormat::AVIF => self.avif,
+            ImageFormat::WEBP => self.webp,
+            ImageFormat::JXL => self.jxl,
+            ImageFormat::JPEG | ImageFormat::PNG | ImageFormat::GIF => true,
+            _ => false,
+        }
+    }
+}
+
+// Past this many distinct colors an image is treated as photographic
+const PHOTOGRAPHIC_COLORS: usize = 1024;
+// Roughly how many pixels are sampled when counting colors
+const COLOR_SAMPLES: usize = 65536;
+
+#[derive(Debug, PartialEq)]
+pub struct ImageTraits {
+    pub animated: bool,
+    pub has_alpha: bool,
+    pub photographic: bool,
+}
+
+pub fn analyze_image(image: &ImageFrames) -> ImageTraits {
+    let first = image.first();
+    let has_alpha = first.pixels().any(|pixel| pixel[3] < 255);
+    let pixel_count = (first.width() * first.height()) as usize;
+    let step = (pixel_count / COLOR_SAMPLES).max(1);
+    let mut colors = HashSet::new();
+    for pixel in first.pixels().step_by(step) {
+        colors.insert(pixel.0);
+        if colors.len() >= PHOTOGRAPHIC_COLORS {
+            break;
+        }
+    }
+    ImageTraits {
+        animated: image.is_animated(),
+        has_alpha,
+        photographic: colors.len() >= PHOTOGRAPHIC_COLORS,
+    }
+}
+
+// Picks the first format in order of preference that the client accepts
+// and that the encoder options can be used with
+pub fn choose_format(
+    traits: &ImageTraits,
+    accepted: &AcceptedFormats,
+    options: &EncoderOptions,
+) -> ImageFormat {
+    use ImageFormat::*;
+    let preferences = if traits.animated {
+        vec![WEBP, GIF]
+    } else if traits.photographic && traits.has_alpha {
+        vec![AVIF, JXL, WEBP, PNG]
+    } else if traits.photographic {
+        vec![AVIF, JXL, WEBP, JPEG]
+    } else {
+        // Flat colors compress well losslessly
+        vec![WEBP, PNG]
+    };
+    preferences
+        .into_iter()
+        .find(|format| accepted.accepts(format) && options.check_format(format).is_ok())
+        .unwrap_or(PNG)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_header_is_parsed() {
+        let accepted = AcceptedFormats::from_accept(
+            "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8",
+        );
+        assert_eq!(
+            AcceptedFormats {
+                avif: true,
+                webp: true,
+                jxl: false
+            },
+            accepted
+        );
+        let accepted = AcceptedFormats::from_accept("image/webp;q=0, image/jxl");
+        assert!(!accepted.webp);
+        assert!(accepted.jxl);
+        assert!(!AcceptedFormats::from_accept("*/*").avif);
+    }
+
+    #[test]
+    fn formats_are_chosen_by_traits_and_support() {
+        let photo = ImageTraits {
+            animated: false,
+            has_alpha: false,
+            photographic: true,
+        };
+        let options = EncoderOptions::default();
+        assert_eq!(
+            ImageFormat::AVIF,
+            choose_format(&photo, &AcceptedFormats::all(), &options)
+        );
+        assert_eq!(
+            ImageFormat::JPEG,
+            choose_format(&photo, &AcceptedFormats::from_accept("*/*"), &options)
+        );
+        let lossless = EncoderOptions {
+            lossless: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            ImageFormat::JXL,
+            choose_format(&photo, &AcceptedFormats::all(), &lossless)
+        );
+        let animated = ImageTraits {
+            animated: true,
+            has_alpha: true,
+            photographic: false,
+        };
+        assert_eq!(
+            ImageFormat::GIF,
+            choose_format(&animated, &AcceptedFormats::from_accept("*/*"), &options)
+        );
+    }
+}
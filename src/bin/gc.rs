@@ -0,0 +1,45 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+extern crate media_server;
+use media_server::*;
+
+// Reports unreferenced objects and orphaned files, pass --delete to remove them
+fn main() {
+    dotenv::dotenv().ok();
+    let delete = std::env::args().skip(1).any(|arg| arg == "--delete");
+    let pool = connect_pool();
+    let conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(err) => {
+            eprintln!("Could not connect: {}", err);
+            std::process::exit(1);
+        }
+    };
+    match blocking_collect_garbage(&conn, !delete) {
+        Ok(report) => match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(err) => {
+                eprintln!("Could not write report: {}", err);
+                std::process::exit(1);
+            }
+        },
+        Err(err) => {
+            eprintln!("Garbage collection failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -57,7 +57,10 @@ fn favicon() -> Result<ByteContent, String> {
 }
 
 #[allow(clippy::too_many_arguments)]
-#[put("/object/<input_path..>?<width>&<height>&<enc>&<ext>", data = "<file>")]
+#[put(
+    "/object/<input_path..>?<width>&<height>&<enc>&<ext>&<sanitize>",
+    data = "<file>"
+)]
 async fn upload_object(
     input_path: PathBuf,
     file: Form<TempFile<'_>>,
@@ -67,6 +70,8 @@ async fn upload_object(
     image_semaphore: &State<ImageSemaphore>,
     enc: Option<ContentEncodingValue>,
     ext: Option<&str>,
+    sanitize: Option<bool>,
+    actor: Actor,
 ) -> Result<Json<models::UpsertObjectResponse>, String> {
     let conn = pool.get().map_err(|e| format!("{}", e))?;
     let path = input_path
@@ -119,18 +124,50 @@ async fn upload_object(
     let temp_path = file
         .path()
         .ok_or_else(|| "File upload is unsupported".to_string())?;
+    // SVG documents may be sanitized before they are stored,
+    // the hash is of the sanitized content
+    let sanitized = if sanitize.unwrap_or(false) && content_type_str == "image/svg+xml" {
+        if encoding != ContentEncodingValue::Identity {
+            return Err(format!(
+                "Cannot sanitize SVG with content encoding {}",
+                encoding
+            ));
+        }
+        let data = tokio::fs::read(temp_path)
+            .await
+            .map_err(|e| format!("{}", e))?;
+        let sanitized = sanitize_svg(&data)?;
+        println!(
+            "Sanitized SVG from {} to {} bytes",
+            data.len(),
+            sanitized.len()
+        );
+        Some(sanitized)
+    } else {
+        None
+    };
     // Read temp file and generate a content hash (will be used as etag too)
-    let content_hash = keyed_hash_file_b64(temp_path).await?;
+    let content_hash = match &sanitized {
+        Some(bytes) => keyed_hash_bytes_b64(bytes)?,
+        None => keyed_hash_file_b64(temp_path).await?,
+    };
 
     // Build internal file path
     let file_path = format!("{}.{}", &content_hash[..20], fs_ext);
     let virtual_object_path = &content_hash[..20];
     destination.push(&file_path);
 
-    let length = file.len() as i64;
-
     // Always overwrite the file
-    copy_temp(temp_path, &destination).await?;
+    let length = match &sanitized {
+        Some(bytes) => {
+            write_bytes_to_file(&destination, bytes).await?;
+            bytes.len() as i64
+        }
+        None => {
+            copy_temp(temp_path, &destination).await?;
+            file.len() as i64
+        }
+    };
 
     let mut width = width;
     let mut height = height;
@@ -173,13 +210,25 @@ async fn upload_object(
         set_primary_object_if_none(&conn, virtual_object.id, object.id)?;
     }
 
+    let (mut rederived, mut rederive_failed) = (Vec::new(), Vec::new());
+    let (mut derived, mut derive_failed) = (Vec::new(), Vec::new());
     if !path.is_empty() {
         let virtual_object = find_or_create_virtual_object_by_object_path(&conn, path)?;
-        replace_virtual_object_relations(&conn, &objects, &virtual_object)?;
-        if let Some(object) = objects.get(0) {
-            println!("Setting primary object {} to {}", path, object.file_path);
-            set_primary_object(&conn, virtual_object.id, object.id)?;
-        }
+        revise_virtual_object(&conn, &virtual_object, actor.0.as_deref(), "upload", || {
+            replace_virtual_object_relations(&conn, &objects, &virtual_object)?;
+            if let Some(object) = objects.get(0) {
+                println!("Setting primary object {} to {}", path, object.file_path);
+                set_primary_object(&conn, virtual_object.id, object.id)?;
+            }
+            clear_derived_virtual_object(&conn, virtual_object.id)
+        })?;
+        (derived, derive_failed) =
+            apply_variant_recipes(&virtual_object, actor.0.as_deref(), image_semaphore, pool)
+                .await?;
+        // Anything else derived from the previous content is derived again
+        (rederived, rederive_failed) =
+            rederive_virtual_objects(&virtual_object, actor.0.as_deref(), image_semaphore, pool)
+                .await?;
     }
 
     Ok(Json(models::UpsertObjectResponse {
@@ -189,6 +238,10 @@ async fn upload_object(
         content_length: upserted_object.length,
         width: upserted_object.width,
         height: upserted_object.height,
+        rederived,
+        rederive_failed,
+        derived,
+        derive_failed,
     }))
 }
 
@@ -197,25 +250,170 @@ async fn upsert_virtual_object(
     input_path: PathBuf,
     body: Json<models::UpsertVirtualObjectRequest>,
     pool: &State<Pool>,
+    actor: Actor,
 ) -> Result<String, String> {
     let path = input_path
         .to_str()
         .ok_or_else(|| "Could not parse path for some reason".to_string())?;
     let conn = pool.get().map_err(|e| format!("{}", e))?;
     let virtual_object = find_or_create_virtual_object_by_object_path(&conn, path)?;
-    let mut objects = Vec::with_capacity(body.objects.len());
-    // This is technically an N query, but N < 20
-    // can reduce with map, and_then, collect, ok_or_else
-    for object in &body.objects {
-        match find_object_by_file_path(&conn, &object.path)? {
-            None => return Err(format!("Could not find object by path {}", object.path)),
-            Some(ob) => objects.push(ob),
+    let objects = match &body.objects {
+        None => None,
+        Some(body_objects) => {
+            let mut objects = Vec::with_capacity(body_objects.len());
+            // This is technically an N query, but N < 20
+            // can reduce with map, and_then, collect, ok_or_else
+            for object in body_objects {
+                match find_object_by_file_path(&conn, &object.path)? {
+                    None => return Err(format!("Could not find object by path {}", object.path)),
+                    Some(ob) => objects.push(ob),
+                }
+            }
+            Some(objects)
+        }
+    };
+    // Checked before anything changes, against the relations it will have
+    let primary = match body.primary_object.as_deref() {
+        None => None,
+        Some("") => Some(None),
+        Some(primary_path) => {
+            let related = match &objects {
+                Some(objects) => objects.clone(),
+                None => find_related_objects_to_virtual_object(&conn, &virtual_object)?,
+            };
+            match related.iter().find(|o| o.file_path == primary_path) {
+                None => return Err(format!("{} is not related to {}", primary_path, path)),
+                Some(object) => Some(Some(object.id)),
+            }
+        }
+    };
+    if objects.is_some() || primary.is_some() {
+        revise_virtual_object(&conn, &virtual_object, actor.0.as_deref(), "update", || {
+            if let Some(objects) = &objects {
+                replace_virtual_object_relations(&conn, objects, &virtual_object)?;
+            }
+            match primary {
+                None => {}
+                Some(None) => clear_primary_object(&conn, virtual_object.id)?,
+                Some(Some(object_id)) => set_primary_object(&conn, virtual_object.id, object_id)?,
+            }
+            clear_derived_virtual_object(&conn, virtual_object.id)
+        })?;
+    }
+    if let Some(default_jpeg_bg) = &body.default_jpeg_bg {
+        let default_jpeg_bg = if default_jpeg_bg.is_empty() {
+            None
+        } else {
+            // Stored the same way as a background transformation
+            Some(format!("{:06x}", parse_hex_color(default_jpeg_bg)?))
+        };
+        set_default_jpeg_bg(&conn, virtual_object.id, default_jpeg_bg)?;
+    }
+    Ok("OK".to_string())
+}
+
+#[put("/virtual-object-alias/<input_path..>", data = "<body>")]
+async fn update_virtual_object_alias(
+    _token: AdminToken,
+    input_path: PathBuf,
+    body: Json<models::UpdateVirtualObjectAliasRequest>,
+    pool: &State<Pool>,
+) -> Result<String, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    let virtual_object = find_or_create_virtual_object_by_object_path(&conn, path)?;
+    match &body.alias_of {
+        Some(alias_path) if alias_path.is_empty() => {
+            set_virtual_object_alias(&conn, &virtual_object, None, None)?;
+        }
+        Some(alias_path) => match find_virtual_object_by_object_path(&conn, alias_path)? {
+            None => return Err(format!("Could not find virtual object {}", alias_path)),
+            Some(target) => {
+                // An alias is resolved first, whatever is related stops being served
+                let related = find_related_objects_to_virtual_object(&conn, &virtual_object)?;
+                if !related.is_empty() && !body.hide_objects {
+                    return Err(format!(
+                        "{} has {} objects, set hideObjects to alias it anyway",
+                        path,
+                        related.len()
+                    ));
+                }
+                // 0 means served in place, the same as leaving it out
+                let redirect = body.redirect.filter(|redirect| *redirect != 0);
+                set_virtual_object_alias(&conn, &virtual_object, Some(&target), redirect)?;
+            }
+        },
+        None => {
+            if let Some(redirect) = body.redirect {
+                // Changes how an existing alias is resolved
+                let target = virtual_object
+                    .alias_virtual_object_id
+                    .map(|id| find_virtual_object_by_id(&conn, id))
+                    .transpose()?
+                    .flatten()
+                    .ok_or_else(|| format!("{} is not an alias", path))?;
+                let redirect = if redirect == 0 { None } else { Some(redirect) };
+                set_virtual_object_alias(&conn, &virtual_object, Some(&target), redirect)?;
+            }
         }
     }
-    replace_virtual_object_relations(&conn, &objects, &virtual_object)?;
     Ok("OK".to_string())
 }
 
+#[delete("/virtual-object/<input_path..>")]
+async fn remove_virtual_object(
+    _token: AdminToken,
+    input_path: PathBuf,
+    pool: &State<Pool>,
+) -> Result<String, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    match find_virtual_object_by_object_path(&conn, path)? {
+        None => Err("not found".to_string()),
+        Some(vobj) => {
+            delete_virtual_object(&conn, &vobj)?;
+            Ok("OK".to_string())
+        }
+    }
+}
+
+#[delete("/object/<input_path..>?<cascade>")]
+async fn remove_object(
+    _token: AdminToken,
+    input_path: PathBuf,
+    cascade: Option<bool>,
+    pool: &State<Pool>,
+) -> Result<Json<models::DeleteObjectResponse>, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    let object = match find_object_by_file_path(&conn, path)? {
+        None => return Err("not found".to_string()),
+        Some(object) => object,
+    };
+    let deleted = delete_object(&conn, &object, cascade.unwrap_or(false))?;
+    let mut response = models::DeleteObjectResponse {
+        deleted_objects: Vec::with_capacity(deleted.len()),
+        removed_files: Vec::with_capacity(deleted.len()),
+    };
+    for object in deleted {
+        // Files are shared by path, another row may still use it
+        if !file_path_is_referenced(&conn, &object.file_path)? {
+            match remove_upload_file(&object.file_path).await {
+                Ok(()) => response.removed_files.push(object.file_path.clone()),
+                Err(err) => println!("Could not remove {}: {}", object.file_path, err),
+            }
+        }
+        response.deleted_objects.push(object.file_path);
+    }
+    Ok(Json(response))
+}
+
 #[get("/virtual-object/<input_path..>")]
 async fn get_virtual_object(
     input_path: PathBuf,
@@ -233,8 +431,19 @@ async fn get_virtual_object(
             println!("Found vobj {:?}", vobj);
             let objects = find_related_objects_to_virtual_object(&conn, &vobj)?;
             println!("Found objects: {:?}", objects);
+            let primary_object = find_primary_object(&conn, &vobj)?.map(|o| o.file_path);
+            let tags = find_tags_for_virtual_object(&conn, &vobj)?;
+            let alias_of = match vobj.alias_virtual_object_id {
+                None => None,
+                Some(id) => find_virtual_object_by_id(&conn, id)?.map(|v| v.object_path),
+            };
             Ok(Json(models::VirtualObjectInfoResponse {
                 path: vobj.object_path,
+                default_jpeg_bg: vobj.default_jpeg_bg,
+                primary_object,
+                tags,
+                alias_of,
+                redirect: vobj.redirect_status,
                 objects: objects
                     .into_iter()
                     .map(|o| models::VirtualObjectInfoResponseObject {
@@ -251,12 +460,342 @@ async fn get_virtual_object(
     }
 }
 
+#[post("/virtual-object-move/<input_path..>", data = "<body>")]
+async fn move_virtual_object(
+    _token: AdminToken,
+    input_path: PathBuf,
+    body: Json<models::MoveVirtualObjectRequest>,
+    pool: &State<Pool>,
+) -> Result<Json<models::MoveVirtualObjectResponse>, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    let moved = move_virtual_objects(
+        &conn,
+        path,
+        &body.to,
+        body.prefix.unwrap_or(false),
+        body.redirect,
+    )?;
+    Ok(Json(models::MoveVirtualObjectResponse {
+        moved: moved
+            .into_iter()
+            .map(|(from, to)| models::MovedVirtualObject { from, to })
+            .collect(),
+    }))
+}
+
+#[post("/virtual-object-copy/<input_path..>", data = "<body>")]
+async fn copy_virtual_object_to(
+    _token: AdminToken,
+    input_path: PathBuf,
+    body: Json<models::CopyVirtualObjectRequest>,
+    pool: &State<Pool>,
+) -> Result<String, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    match find_virtual_object_by_object_path(&conn, path)? {
+        None => Err("not found".to_string()),
+        Some(vobj) => {
+            copy_virtual_object(&conn, &vobj, &body.to)?;
+            Ok("OK".to_string())
+        }
+    }
+}
+
+#[put("/variant-recipes/<input_path..>?<prefix>", data = "<body>")]
+async fn put_variant_recipes(
+    _token: AdminToken,
+    input_path: PathBuf,
+    prefix: Option<bool>,
+    body: String,
+    pool: &State<Pool>,
+) -> Result<String, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    set_variant_recipes(&conn, path, prefix.unwrap_or(false), &body)?;
+    Ok("OK".to_string())
+}
+
+#[get("/variant-recipes/<input_path..>")]
+async fn get_variant_recipes(
+    input_path: PathBuf,
+    pool: &State<Pool>,
+) -> Result<Json<models::VariantRecipesResponse>, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    let mut recipes = Vec::new();
+    for recipe in find_variant_recipes_for_path(&conn, path)? {
+        recipes.push(models::VariantRecipeResponse {
+            recipes: serde_json::from_str(&recipe.recipes).map_err(|e| format!("{}", e))?,
+            path: recipe.path,
+            prefix: recipe.prefix,
+        });
+    }
+    Ok(Json(models::VariantRecipesResponse { recipes }))
+}
+
+#[delete("/variant-recipes/<input_path..>?<prefix>")]
+async fn delete_variant_recipes(
+    _token: AdminToken,
+    input_path: PathBuf,
+    prefix: Option<bool>,
+    pool: &State<Pool>,
+) -> Result<String, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    if remove_variant_recipes(&conn, path, prefix.unwrap_or(false))? {
+        Ok("OK".to_string())
+    } else {
+        Err("not found".to_string())
+    }
+}
+
+#[get("/lineage/object/<input_path..>")]
+async fn object_lineage(
+    input_path: PathBuf,
+    pool: &State<Pool>,
+) -> Result<Json<models::ObjectLineageResponse>, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    match find_object_by_file_path(&conn, path)? {
+        None => Err("not found".to_string()),
+        Some(object) => Ok(Json(build_object_lineage(&conn, &object)?)),
+    }
+}
+
+#[get("/lineage/virtual-object/<input_path..>")]
+async fn virtual_object_lineage(
+    input_path: PathBuf,
+    pool: &State<Pool>,
+) -> Result<Json<models::VirtualObjectLineageResponse>, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    match find_virtual_object_by_object_path(&conn, path)? {
+        None => Err("not found".to_string()),
+        Some(vobj) => Ok(Json(build_virtual_object_lineage(&conn, &vobj)?)),
+    }
+}
+
+#[get("/virtual-object-revisions/<input_path..>")]
+async fn list_revisions(
+    input_path: PathBuf,
+    pool: &State<Pool>,
+) -> Result<Json<models::VirtualObjectRevisionsResponse>, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    let vobj = match find_virtual_object_by_object_path(&conn, path)? {
+        None => return Err("not found".to_string()),
+        Some(vobj) => vobj,
+    };
+    let mut revisions = Vec::new();
+    for (revision, objects) in find_revisions(&conn, &vobj)? {
+        let primary_object = match revision.primary_object_id {
+            None => None,
+            Some(id) => match objects.iter().find(|o| o.id == id) {
+                Some(object) => Some(object.file_path.clone()),
+                None => find_object_by_id(&conn, id)?.map(|o| o.file_path),
+            },
+        };
+        revisions.push(models::VirtualObjectRevisionResponse {
+            id: revision.id,
+            created: revision.created,
+            actor: revision.actor,
+            action: revision.action,
+            primary_object,
+            objects: objects.into_iter().map(|o| o.file_path).collect(),
+        });
+    }
+    Ok(Json(models::VirtualObjectRevisionsResponse {
+        path: vobj.object_path,
+        revisions,
+    }))
+}
+
+#[post("/virtual-object-rollback/<input_path..>?<revision>")]
+async fn rollback_revision(
+    _token: AdminToken,
+    input_path: PathBuf,
+    revision: i32,
+    pool: &State<Pool>,
+    actor: Actor,
+) -> Result<String, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    match find_virtual_object_by_object_path(&conn, path)? {
+        None => Err("not found".to_string()),
+        Some(vobj) => {
+            rollback_virtual_object(&conn, &vobj, revision, actor.0.as_deref())?;
+            Ok("OK".to_string())
+        }
+    }
+}
+
+#[put("/virtual-object-tags/<input_path..>", data = "<body>")]
+async fn update_tags(
+    input_path: PathBuf,
+    body: Json<models::UpdateVirtualObjectTagsRequest>,
+    pool: &State<Pool>,
+) -> Result<Json<models::VirtualObjectTagsResponse>, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let add = body
+        .add
+        .iter()
+        .map(|tag| parse_tag(tag))
+        .collect::<Result<Vec<String>, String>>()?;
+    let remove = body
+        .remove
+        .iter()
+        .map(|tag| parse_tag(tag))
+        .collect::<Result<Vec<String>, String>>()?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    let vobj = match find_virtual_object_by_object_path(&conn, path)? {
+        None => return Err("not found".to_string()),
+        Some(vobj) => vobj,
+    };
+    update_virtual_object_tags(&conn, &vobj, &add, &remove)?;
+    let tags = find_tags_for_virtual_object(&conn, &vobj)?;
+    Ok(Json(models::VirtualObjectTagsResponse {
+        path: vobj.object_path,
+        tags,
+    }))
+}
+
+#[get("/tagged?<all>&<any>&<none>&<after>&<limit>")]
+async fn find_tagged(
+    all: Option<String>,
+    any: Option<String>,
+    none: Option<String>,
+    after: Option<i32>,
+    limit: Option<i64>,
+    pool: &State<Pool>,
+) -> Result<Json<models::TaggedVirtualObjectsResponse>, String> {
+    let all = parse_tag_list(all.as_deref().unwrap_or(""))?;
+    let any = parse_tag_list(any.as_deref().unwrap_or(""))?;
+    let none = parse_tag_list(none.as_deref().unwrap_or(""))?;
+    let limit = limit.unwrap_or(DEFAULT_TAG_PAGE).clamp(1, MAX_TAG_PAGE);
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    // One more than asked for shows whether there is another page
+    let mut vobjs = find_virtual_objects_by_tags(&conn, &all, &any, &none, after, limit + 1)?;
+    let next = if vobjs.len() as i64 > limit {
+        vobjs.truncate(limit as usize);
+        vobjs.last().map(|vobj| vobj.id.to_string())
+    } else {
+        None
+    };
+    let ids: Vec<i32> = vobjs.iter().map(|vobj| vobj.id).collect();
+    let mut tags = find_tags_for_virtual_objects(&conn, &ids)?;
+    Ok(Json(models::TaggedVirtualObjectsResponse {
+        objects: vobjs
+            .into_iter()
+            .map(|vobj| models::VirtualObjectTagsResponse {
+                tags: tags.remove(&vobj.id).unwrap_or_default(),
+                path: vobj.object_path,
+            })
+            .collect(),
+        next,
+    }))
+}
+
+#[get("/list?<prefix>&<delimiter>&<after>&<limit>&<order>")]
+async fn list_objects(
+    prefix: Option<String>,
+    delimiter: Option<String>,
+    after: Option<String>,
+    limit: Option<i64>,
+    order: Option<String>,
+    pool: &State<Pool>,
+) -> Result<Json<models::ListVirtualObjectsResponse>, String> {
+    let descending = match order.as_deref() {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(order) => return Err(format!("Unknown order {}", order)),
+    };
+    let limit = limit.unwrap_or(DEFAULT_LIST_PAGE).clamp(1, MAX_LIST_PAGE);
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    let (entries, next) = list_virtual_objects(
+        &conn,
+        prefix.as_deref().unwrap_or(""),
+        delimiter.as_deref(),
+        after.as_deref(),
+        limit,
+        descending,
+    )?;
+    let mut response = models::ListVirtualObjectsResponse {
+        objects: Vec::with_capacity(entries.len()),
+        prefixes: Vec::new(),
+        next,
+    };
+    // This is technically an N query, but N is at most a page
+    for entry in entries {
+        match entry {
+            ListingEntry::Prefix(prefix) => response.prefixes.push(prefix),
+            ListingEntry::Object(vobj) => {
+                let primary_object = find_primary_object(&conn, &vobj)?.map(|o| {
+                    models::VirtualObjectInfoResponseObject {
+                        path: o.file_path,
+                        content_type: o.content_type,
+                        content_encoding: ContentEncodingValue::from_database(&o.content_encoding),
+                        content_length: o.length,
+                        width: o.width,
+                        height: o.height,
+                    }
+                });
+                response.objects.push(models::ListedVirtualObject {
+                    path: vobj.object_path,
+                    primary_object,
+                });
+            }
+        }
+    }
+    Ok(Json(response))
+}
+
+#[get("/manifest/<input_path..>?<sizes>&<alt>")]
+async fn get_manifest(
+    input_path: PathBuf,
+    sizes: Option<String>,
+    alt: Option<String>,
+    pool: &State<Pool>,
+) -> Result<Json<models::ManifestResponse>, String> {
+    let path = input_path
+        .to_str()
+        .ok_or_else(|| "Could not parse path for some reason".to_string())?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    let vobj = match find_virtual_object_by_object_path(&conn, path)? {
+        None => return Err("not found".to_string()),
+        Some(vobj) => vobj,
+    };
+    let manifest = build_manifest(&conn, &vobj, sizes.as_deref(), alt.as_deref().unwrap_or(""))?;
+    Ok(Json(manifest))
+}
+
 #[post("/derive-object/<input_path..>", data = "<body>")]
 async fn derive_objects(
     input_path: PathBuf,
     pool: &State<Pool>,
     sem: &State<ImageSemaphore>,
     body: Json<models::DeriveTransformedObjectsRequest>,
+    actor: Actor,
 ) -> Result<Json<models::DeriveTransformedObjectsResponse>, String> {
     let path = input_path
         .to_str()
@@ -267,89 +806,27 @@ async fn derive_objects(
         None => return Err("not found".to_string()),
         Some(vobj) => vobj,
     };
-    let object_id = match vobj.primary_object_id {
-        None => {
-            return Err("Finding dominant object not implemented".to_string());
-        }
-        Some(id) => id,
-    };
-
-    let obj = if let Some(object) = find_object_by_id(&conn, object_id)? {
-        object
-    } else {
-        return Err("Internal error, could not find object".to_string());
+    let obj = match find_primary_object(&conn, &vobj)? {
+        Some(object) => object,
+        None => return Err(format!("{} has no objects", path)),
     };
 
     let mut response = models::DeriveTransformedObjectsResponse {
         objects: Vec::with_capacity(body.objects.len()),
         blur_hash: Vec::with_capacity(body.blur_hash.len()),
     };
-    let vobj_opt = Some(&vobj);
     for derived_object in &body.objects {
-        let transforms = derived_object
-            .transforms
-            .clone()
-            .unwrap_or_else(TransformationList::empty);
-        let mut blur_hashes = Vec::with_capacity(derived_object.blur_hash.len());
-        match derive_transformed_image(
+        let derived = derive_variant(
             &obj,
-            vobj_opt,
-            transforms,
-            derived_object.quality,
-            derived_object.content_type.parse::<ImageFormat>().ok(),
+            &vobj,
+            derived_object,
+            &derived_object.path,
+            actor.0.as_deref(),
             sem,
             pool,
         )
-        .await
-        {
-            Ok((object, virtual_object)) => {
-                // TODO refactor
-                let vobj =
-                    find_or_create_virtual_object_by_object_path(&conn, &derived_object.path)?;
-                let update = models::UpdateTransformedVirtualObject {
-                    default_jpeg_bg: virtual_object.default_jpeg_bg,
-                    derived_virtual_object_id: virtual_object.derived_virtual_object_id,
-                    primary_object_id: Some(object.id),
-                    transforms: object.transforms.clone(),
-                    transforms_hash: object.transforms_hash.clone(),
-                };
-
-                let objects = vec![object.clone()];
-                replace_virtual_object_relations(&conn, &objects, &vobj)?;
-                println!("Replaced object relations {:?}", objects);
-                println!("Updating virtual object {:?}", update);
-                update_transformed_virtual_object(&conn, vobj.id, update)?;
-                if let Some(object) = objects.get(0) {
-                    for blur_hash in &derived_object.blur_hash {
-                        let bg = blur_hash.bg.clone();
-                        let hash = create_blur_hash(
-                            object,
-                            blur_hash.x.unwrap_or(3),
-                            blur_hash.y.unwrap_or(3),
-                            blur_hash.bg.clone(),
-                            sem,
-                            pool,
-                        )
-                        .await?;
-                        blur_hashes.push(models::DeriveTransformedObjectsResponseBlurHash {
-                            x: blur_hash.x,
-                            y: blur_hash.y,
-                            bg,
-                            hash,
-                        });
-                    }
-                }
-            }
-            Err(e) => {
-                return Err(e);
-            }
-        }
-        response
-            .objects
-            .push(models::DeriveTransformedObjectsResponseObject {
-                path: derived_object.path.clone(),
-                blur_hash: blur_hashes,
-            });
+        .await?;
+        response.objects.push(derived);
 
         println!("Output {:?}", derived_object);
     }
@@ -397,23 +874,15 @@ async fn blur_hash(
             return Err(format!("Could not find {}", path));
         }
     };
-    let object_id = match virtual_object.primary_object_id {
-        None => {
-            return Err("Finding dominant object not implemented".to_string());
-        }
-        Some(id) => id,
+    let object = match find_primary_object(&conn, &virtual_object)? {
+        Some(object) => object,
+        None => return Err(format!("{} has no objects", path)),
     };
     let bg_str = bg.unwrap_or_else(|| "".to_string());
-    if let Some(hash) = find_blur_hash(&conn, object_id, x.unwrap_or(3), y.unwrap_or(3), &bg_str)? {
+    if let Some(hash) = find_blur_hash(&conn, object.id, x.unwrap_or(3), y.unwrap_or(3), &bg_str)? {
         return Ok(hash);
     };
 
-    let object = if let Some(object) = find_object_by_id(&conn, object_id)? {
-        object
-    } else {
-        return Err("Internal error, could not find object".to_string());
-    };
-
     let hash = create_blur_hash(
         &object,
         x.unwrap_or(3),
@@ -432,7 +901,7 @@ fn rocket() -> _ {
     dotenv::dotenv().ok();
     let connection_pool = connect_pool();
     let image_semaphore = ImageSemaphore::new(1);
-    rocket::build()
+    let rocket = rocket::build()
         .manage(connection_pool)
         .manage(image_semaphore)
         .mount(
@@ -443,7 +912,23 @@ fn rocket() -> _ {
                 robots_txt,
                 upload_object,
                 upsert_virtual_object,
+                update_virtual_object_alias,
                 get_virtual_object,
+                remove_virtual_object,
+                remove_object,
+                move_virtual_object,
+                copy_virtual_object_to,
+                list_revisions,
+                object_lineage,
+                put_variant_recipes,
+                get_variant_recipes,
+                delete_variant_recipes,
+                virtual_object_lineage,
+                rollback_revision,
+                get_manifest,
+                update_tags,
+                find_tagged,
+                list_objects,
                 derive_objects,
                 blur_hash,
             ],
@@ -451,4 +936,9 @@ fn rocket() -> _ {
         .mount("/", ExistingFileHandler())
         .attach(rocket::shield::Shield::new())
         .attach(ServerName::new("Cendyne Media"))
+        .attach(ClientHints);
+    match GarbageCollector::from_env() {
+        Some(collector) => rocket.attach(collector),
+        None => rocket,
+    }
 }
@@ -32,6 +32,7 @@ pub struct ByteContent {
     content_type: (&'static str, &'static str),
     content_encoding: ContentEncodingValue,
     cache_max_age: Option<u32>,
+    vary: Vec<&'static str>,
 }
 
 impl ByteContent {
@@ -46,6 +47,7 @@ impl ByteContent {
             content_type,
             content_encoding,
             cache_max_age,
+            vary: Vec::new(),
         })
     }
     pub fn from_static_bytes(
@@ -59,8 +61,14 @@ impl ByteContent {
             content_type,
             content_encoding,
             cache_max_age,
+            vary: Vec::new(),
         })
     }
+    // Request headers that changed the content
+    pub fn vary(mut self, header: &'static str) -> Self {
+        self.vary.push(header);
+        self
+    }
 }
 
 impl<'r> Responder<'r, 'static> for ByteContent {
@@ -88,6 +96,9 @@ impl<'r> Responder<'r, 'static> for ByteContent {
             }
         }
         response_builder.raw_header("Age", "0");
+        if !self.vary.is_empty() {
+            response_builder.raw_header("Vary", self.vary.join(", "));
+        }
         // No last modified, this is on demand
         if let Some(max_age) = self.cache_max_age {
             response_builder.raw_header("Cache-Control", format!("public, max-age={}", max_age));
@@ -0,0 +1,124 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::parsing::valid_dpr;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Request, Response};
+
+pub const HINT_WIDTH: &str = "Sec-CH-Width";
+pub const HINT_DPR: &str = "Sec-CH-DPR";
+pub const HINT_VIEWPORT_WIDTH: &str = "Sec-CH-Viewport-Width";
+pub const HINT_SAVE_DATA: &str = "Save-Data";
+
+// Quality used for lossy formats when the client asks to save data
+// and no quality was requested
+pub const SAVE_DATA_QUALITY: u8 = 50;
+
+// Browsers only send the hints after the server asks for them
+pub struct ClientHints;
+
+#[rocket::async_trait]
+impl Fairing for ClientHints {
+    fn info(&self) -> Info {
+        Info {
+            name: "Advertises client hints",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_header(Header::new(
+            "Accept-CH",
+            [HINT_WIDTH, HINT_DPR, HINT_VIEWPORT_WIDTH].join(", "),
+        ));
+    }
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct ClientHintValues {
+    // Width of the image on screen in device pixels
+    pub width: Option<i32>,
+    pub dpr: Option<f32>,
+    // Width of the viewport in CSS pixels
+    pub viewport_width: Option<i32>,
+    pub save_data: bool,
+}
+
+fn parse_width(value: Option<&str>) -> Option<i32> {
+    value
+        .and_then(|value| value.trim().parse::<i32>().ok())
+        .filter(|width| *width > 0)
+}
+
+impl ClientHintValues {
+    pub fn from_headers(
+        width: Option<&str>,
+        dpr: Option<&str>,
+        viewport_width: Option<&str>,
+        save_data: Option<&str>,
+    ) -> Self {
+        ClientHintValues {
+            width: parse_width(width),
+            dpr: dpr
+                .and_then(|value| value.trim().parse::<f32>().ok())
+                .and_then(valid_dpr),
+            viewport_width: parse_width(viewport_width),
+            save_data: save_data
+                .map(|value| value.trim().eq_ignore_ascii_case("on"))
+                .unwrap_or(false),
+        }
+    }
+
+    pub fn from_request(req: &Request<'_>) -> Self {
+        let headers = req.headers();
+        Self::from_headers(
+            headers.get_one(HINT_WIDTH),
+            headers.get_one(HINT_DPR),
+            headers.get_one(HINT_VIEWPORT_WIDTH),
+            headers.get_one(HINT_SAVE_DATA),
+        )
+    }
+
+    // The width to select for in device pixels when none was requested
+    pub fn device_width(&self) -> Option<i32> {
+        self.width.or_else(|| {
+            self.viewport_width
+                .map(|width| (width as f32 * self.dpr.unwrap_or(1.0)).round() as i32)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hints_are_parsed() {
+        let hints = ClientHintValues::from_headers(None, Some("2"), Some("400"), Some("on"));
+        assert_eq!(Some(2.0), hints.dpr);
+        assert!(hints.save_data);
+        assert_eq!(Some(800), hints.device_width());
+        let hints = ClientHintValues::from_headers(Some("300"), Some("9"), Some("400"), None);
+        assert_eq!(None, hints.dpr);
+        assert!(!hints.save_data);
+        assert_eq!(Some(300), hints.device_width());
+        assert_eq!(
+            ClientHintValues::default(),
+            ClientHintValues::from_headers(Some("-1"), Some("x"), None, Some("off"))
+        );
+    }
+}
@@ -80,6 +80,17 @@ impl ContentEncodingValue {
             ContentEncodingValue::Default => "",
         }
     }
+    // Every value from_database reads as this encoding, None for Default
+    pub fn database_values(&self) -> Option<&'static [&'static str]> {
+        match self {
+            ContentEncodingValue::Identity => Some(&["id", "identity", ""]),
+            ContentEncodingValue::Gzip => Some(&["gz", "gzip"]),
+            ContentEncodingValue::Compress => Some(&["z", "compress"]),
+            ContentEncodingValue::Deflate => Some(&["zl", "deflate", "zip"]),
+            ContentEncodingValue::Brotli => Some(&["br"]),
+            ContentEncodingValue::Default => None,
+        }
+    }
     pub fn from_extension(ext: &str) -> ContentEncodingValue {
         match ext {
             "" => ContentEncodingValue::Identity,
@@ -22,6 +22,7 @@ pub const SAFE_EXTS: phf::Set<&'static str> = phf_set! {
     "aac",
     "avif",
     "bin",
+    "bmp",
     "bz",
     "bz2",
     "css",
@@ -44,6 +45,7 @@ pub const SAFE_EXTS: phf::Set<&'static str> = phf_set! {
     "otf",
     "pdf",
     "png",
+    "qoi",
     "svg",
     "tar",
     "ttf",
@@ -64,6 +66,7 @@ pub const EXTENSION_CONTENT_TYPES: phf::Map<&'static str, (&'static str, &'stati
     "aac" => ("audio", "aac"),
     "avif" => ("image", "avif"),
     "bin" => ("application", "octet-stream"),
+    "bmp" => ("image", "bmp"),
     "bz" => ("application", "x-bzip"),
     "bz2" => ("application", "x-bzip2"),
     "css" => ("text", "css"),
@@ -88,6 +91,7 @@ pub const EXTENSION_CONTENT_TYPES: phf::Map<&'static str, (&'static str, &'stati
     "otf" => ("font", "otf"),
     "pdf" => ("application", "pdf"),
     "png" => ("image", "png"),
+    "qoi" => ("image", "qoi"),
     "svg" => ("image", "svg+xml"),
     "tar" => ("application", "x-tar"),
     "ttf" => ("font", "ttf"),
@@ -135,6 +139,10 @@ pub const IMAGE_TYPE_EXTENSIONS: phf::Map<&'static str, &'static str> = phf_map!
     "svg+xml" => "svg",
     "webp" => "webp",
     "bmp" => "bmp",
+    "vnd.microsoft.icon" => "ico",
+    "x-icon" => "ico",
+    "qoi" => "qoi",
+    "x-qoi" => "qoi",
 };
 
 pub const VIDEO_TYPE_EXTENSIONS: phf::Map<&'static str, &'static str> = phf_map! {
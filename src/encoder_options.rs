@@ -0,0 +1,302 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::image_operations::ImageFormat;
+use rocket::serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+pub const MAX_EFFORT: u8 = 10;
+pub const MIN_PALETTE: u16 = 2;
+pub const MAX_PALETTE: u16 = 256;
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+pub enum ChromaSubsampling {
+    #[serde(rename = "444")]
+    Yuv444,
+    #[serde(rename = "422")]
+    Yuv422,
+    #[serde(rename = "420")]
+    Yuv420,
+}
+
+// Options beyond quality that change how an image is encoded.
+// Encoded images never carry metadata from the source, so stripping is implied.
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(try_from = "EncoderOptionsFields")]
+pub struct EncoderOptions {
+    pub progressive: bool,
+    pub subsampling: Option<ChromaSubsampling>,
+    pub lossless: bool,
+    // 0 is fastest, 10 is slowest and smallest
+    pub effort: Option<u8>,
+    // Lossless PNG optimization
+    pub optimize: bool,
+    // Maximum colors in the palette for GIF and PNG
+    pub palette: Option<u16>,
+    pub dither: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EncoderOptionsFields {
+    #[serde(default)]
+    progressive: bool,
+    subsampling: Option<ChromaSubsampling>,
+    #[serde(default)]
+    lossless: bool,
+    effort: Option<u8>,
+    strip: Option<bool>,
+    #[serde(default)]
+    optimize: bool,
+    palette: Option<u16>,
+    #[serde(default)]
+    dither: bool,
+}
+
+impl TryFrom<EncoderOptionsFields> for EncoderOptions {
+    type Error = String;
+    fn try_from(fields: EncoderOptionsFields) -> Result<Self, Self::Error> {
+        if fields.strip == Some(false) {
+            return Err("Metadata is always stripped".to_string());
+        }
+        if let Some(effort) = fields.effort {
+            check_effort(effort)?;
+        }
+        if let Some(palette) = fields.palette {
+            check_palette(palette)?;
+        }
+        Ok(EncoderOptions {
+            progressive: fields.progressive,
+            subsampling: fields.subsampling,
+            lossless: fields.lossless,
+            effort: fields.effort,
+            optimize: fields.optimize,
+            palette: fields.palette,
+            dither: fields.dither,
+        })
+    }
+}
+
+fn check_effort(effort: u8) -> Result<(), String> {
+    if effort > MAX_EFFORT {
+        Err(format!("Effort {} is over {}", effort, MAX_EFFORT))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_palette(palette: u16) -> Result<(), String> {
+    if !(MIN_PALETTE..=MAX_PALETTE).contains(&palette) {
+        Err(format!(
+            "Palette size {} is not within {} and {}",
+            palette, MIN_PALETTE, MAX_PALETTE
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+impl EncoderOptions {
+    pub fn is_empty(&self) -> bool {
+        *self == EncoderOptions::default()
+    }
+
+    pub fn check_format(&self, format: &ImageFormat) -> Result<(), String> {
+        use ImageFormat::*;
+        if self.progressive && *format != JPEG {
+            return Err(format!("{:?} cannot be progressive", format));
+        }
+        if self.subsampling.is_some() && *format != JPEG {
+            return Err(format!("{:?} does not support chroma subsampling", format));
+        }
+        if self.lossless && matches!(format, JPEG | AVIF) {
+            return Err(format!("{:?} cannot be lossless", format));
+        }
+        if self.effort.is_some() && !matches!(format, PNG | GIF | AVIF | WEBP | JXL) {
+            return Err(format!("{:?} does not support effort", format));
+        }
+        if self.optimize && *format != PNG {
+            return Err(format!("{:?} cannot be optimized", format));
+        }
+        if self.palette.is_some() && !matches!(format, PNG | GIF) {
+            return Err(format!("{:?} does not support a palette", format));
+        }
+        if self.palette.is_some() && self.lossless {
+            return Err("A palette cannot be lossless".to_string());
+        }
+        // GIF always has a palette, PNG only when asked for one
+        if self.dither && !(*format == GIF || (*format == PNG && self.palette.is_some())) {
+            return Err(format!("{:?} cannot be dithered without a palette", format));
+        }
+        Ok(())
+    }
+
+    // Options are stored with the object when any are set
+    pub fn to_database(&self) -> Option<String> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.to_string())
+        }
+    }
+}
+
+impl fmt::Display for ChromaSubsampling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChromaSubsampling::Yuv444 => write!(f, "444"),
+            ChromaSubsampling::Yuv422 => write!(f, "422"),
+            ChromaSubsampling::Yuv420 => write!(f, "420"),
+        }
+    }
+}
+
+impl FromStr for ChromaSubsampling {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "444" => Ok(ChromaSubsampling::Yuv444),
+            "422" => Ok(ChromaSubsampling::Yuv422),
+            "420" => Ok(ChromaSubsampling::Yuv420),
+            _ => Err(format!("Unrecognized chroma subsampling {}", s)),
+        }
+    }
+}
+
+// Written in a fixed order so equal options always produce the same string
+impl fmt::Display for EncoderOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::with_capacity(7);
+        if self.progressive {
+            parts.push("p".to_string());
+        }
+        if let Some(subsampling) = self.subsampling {
+            parts.push(format!("ss{}", subsampling));
+        }
+        if self.lossless {
+            parts.push("ll".to_string());
+        }
+        if let Some(effort) = self.effort {
+            parts.push(format!("e{}", effort));
+        }
+        if self.optimize {
+            parts.push("o".to_string());
+        }
+        if let Some(palette) = self.palette {
+            parts.push(format!("pl{}", palette));
+        }
+        if self.dither {
+            parts.push("d".to_string());
+        }
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+impl FromStr for EncoderOptions {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = EncoderOptions::default();
+        if s.is_empty() {
+            return Ok(options);
+        }
+        for part in s.split(',') {
+            if part == "p" {
+                options.progressive = true;
+            } else if part == "ll" {
+                options.lossless = true;
+            } else if part == "o" {
+                options.optimize = true;
+            } else if part == "d" {
+                options.dither = true;
+            } else if part == "st" {
+                // Already the case, accepted for clarity
+            } else if let Some(palette) = part.strip_prefix("pl") {
+                let palette = palette.parse::<u16>().map_err(|e| format!("{}", e))?;
+                check_palette(palette)?;
+                options.palette = Some(palette);
+            } else if let Some(subsampling) = part.strip_prefix("ss") {
+                options.subsampling = Some(subsampling.parse::<ChromaSubsampling>()?);
+            } else if let Some(effort) = part.strip_prefix('e') {
+                let effort = effort.parse::<u8>().map_err(|e| format!("{}", e))?;
+                check_effort(effort)?;
+                options.effort = Some(effort);
+            } else {
+                return Err(format!("Could not parse {} into an encoder option", part));
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl<'r> rocket::form::FromFormField<'r> for EncoderOptions {
+    fn from_value(field: rocket::form::ValueField<'r>) -> rocket::form::Result<'r, Self> {
+        field
+            .value
+            .parse::<EncoderOptions>()
+            .map_err(|err| rocket::form::Errors::from(rocket::form::Error::validation(err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_encode_in_fixed_order() {
+        let options = "e4,ll,ss420,p".parse::<EncoderOptions>().unwrap();
+        assert_eq!("p,ss420,ll,e4", options.to_string());
+        assert_eq!(Some("p,ss420,ll,e4".to_string()), options.to_database());
+    }
+
+    #[test]
+    fn empty_options_are_not_stored() {
+        let options = "st".parse::<EncoderOptions>().unwrap();
+        assert!(options.is_empty());
+        assert_eq!(None, options.to_database());
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        assert!("e11".parse::<EncoderOptions>().is_err());
+        assert!("ss411".parse::<EncoderOptions>().is_err());
+        assert!("x".parse::<EncoderOptions>().is_err());
+        assert!("pl1".parse::<EncoderOptions>().is_err());
+        assert!("pl257".parse::<EncoderOptions>().is_err());
+    }
+
+    #[test]
+    fn palette_options_encode_after_effort() {
+        let options = "d,pl64,o,e9".parse::<EncoderOptions>().unwrap();
+        assert_eq!("e9,o,pl64,d", options.to_string());
+        assert!(options.check_format(&ImageFormat::PNG).is_ok());
+        assert!(options.check_format(&ImageFormat::GIF).is_err());
+        let options = "d".parse::<EncoderOptions>().unwrap();
+        assert!(options.check_format(&ImageFormat::GIF).is_ok());
+        assert!(options.check_format(&ImageFormat::PNG).is_err());
+    }
+
+    #[test]
+    fn options_are_checked_against_format() {
+        let options = "p,ss444".parse::<EncoderOptions>().unwrap();
+        assert!(options.check_format(&ImageFormat::JPEG).is_ok());
+        assert!(options.check_format(&ImageFormat::PNG).is_err());
+        let options = "ll".parse::<EncoderOptions>().unwrap();
+        assert!(options.check_format(&ImageFormat::WEBP).is_ok());
+        assert!(options.check_format(&ImageFormat::JPEG).is_err());
+    }
+}
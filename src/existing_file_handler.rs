@@ -14,20 +14,26 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::auto_format::AcceptedFormats;
+use crate::client_hints::{HINT_SAVE_DATA, SAVE_DATA_QUALITY};
+use crate::encoder_options::EncoderOptions;
 use crate::image_operations::*;
-use crate::models::Object;
 use crate::models::UpdateTransformedVirtualObject;
+use crate::models::{Object, VirtualObject};
 use crate::object_image::*;
+use crate::parsing::parse_hex_color;
+use crate::quality::Quality;
 use crate::sqlite::Pool;
 use crate::virtual_object::{add_virtual_object_relations, update_transformed_virtual_object};
 use crate::ByteContent;
 use crate::ContentEncodingValue;
 use crate::FileContent;
 use crate::{
-    find_or_create_virtual_object_by_object_path, parse_existing_file_request,
+    find_alias_redirect, find_or_create_virtual_object_by_object_path, parse_existing_file_request,
     search_existing_file_query,
 };
 use rocket::http::{Method, Status};
+use rocket::response::Redirect;
 use rocket::route::{Handler, Outcome, Route};
 use rocket::State;
 use rocket::{Data, Request};
@@ -65,31 +71,101 @@ impl Handler for ExistingFileHandler {
         println!("Transformations? {:?}", query.transformations());
 
         let query_transformations = query.transformations();
+        let dpr = query.dpr();
+        let save_data = query.save_data();
+        let mut vary = query.vary();
 
         let as_path = req.query_value::<String>("as").transpose().unwrap_or(None);
         // Search for virtual object first
-        let object: Object = if let Ok(Some(object)) = search_existing_file_query(&conn, query) {
-            object
-        } else {
-            return Outcome::forward(data);
-        };
+        let (vobj, object): (VirtualObject, Object) =
+            if let Ok(Some(found)) = search_existing_file_query(&conn, &query) {
+                found
+            } else {
+                // Aliases that redirect are not served directly
+                let redirect = match find_alias_redirect(&conn, &query) {
+                    Ok(Some((301, location))) => Redirect::moved(location),
+                    Ok(Some((302, location))) => Redirect::found(location),
+                    Ok(Some((307, location))) => Redirect::temporary(location),
+                    Ok(Some((308, location))) => Redirect::permanent(location),
+                    Ok(_) => return Outcome::forward(data),
+                    Err(err) => {
+                        println!("Could not resolve alias {}", err);
+                        return Outcome::forward(data);
+                    }
+                };
+                return Outcome::from(req, redirect);
+            };
 
         match query_transformations {
             Some(transformations) => {
-                let quality = req.query_value::<u8>("q").transpose().unwrap_or(None);
-                let image_type = req
+                let transformations = match dpr {
+                    Some(dpr) => transformations.with_density(
+                        dpr,
+                        object
+                            .width
+                            .zip(object.height)
+                            .map(|(w, h)| (w as u32, h as u32)),
+                    ),
+                    None => transformations,
+                };
+                let mut quality = req.query_value::<Quality>("q").transpose().unwrap_or(None);
+                // Images saved to another path are shared, so they ignore Save-Data
+                if quality.is_none() && as_path.is_none() {
+                    vary.push(HINT_SAVE_DATA);
+                    if save_data {
+                        quality = Some(Quality::Fixed(SAVE_DATA_QUALITY));
+                    }
+                }
+                let encoder_options = match req.query_value::<EncoderOptions>("eo") {
+                    None => EncoderOptions::default(),
+                    Some(Ok(options)) => options,
+                    Some(Err(err)) => {
+                        println!("Could not parse encoder options {:?}", err);
+                        return Outcome::failure(Status::BadRequest);
+                    }
+                };
+                // Automatic types depend on what the client accepts
+                let image_type = match req
                     .query_value::<ImageFormat>("ty")
                     .transpose()
-                    .unwrap_or(None);
+                    .unwrap_or(None)
+                {
+                    Some(ImageFormat::AUTO(_)) => Some(ImageFormat::AUTO(
+                        AcceptedFormats::from_accept(req.headers().get_one("Accept").unwrap_or("")),
+                    )),
+                    image_type => image_type,
+                };
+                if matches!(image_type, Some(ImageFormat::AUTO(_))) {
+                    // Saved images are shared, one client's Accept cannot pick their type
+                    if as_path.is_some() {
+                        println!("Automatic image types cannot be saved to another path");
+                        return Outcome::failure(Status::BadRequest);
+                    }
+                    vary.push("Accept");
+                }
+                let background = match req
+                    .query_value::<&str>("bg")
+                    .and_then(|bg| bg.ok())
+                    .map(parse_hex_color)
+                {
+                    None => None,
+                    Some(Ok(color)) => Some(color),
+                    Some(Err(err)) => {
+                        println!("Could not parse background {}", err);
+                        return Outcome::failure(Status::BadRequest);
+                    }
+                };
 
                 match as_path {
                     Some(path) => {
                         match derive_transformed_image(
                             &object,
-                            None,
+                            Some(&vobj),
                             transformations,
                             quality,
+                            encoder_options,
                             image_type,
+                            background,
                             sem,
                             pool,
                         )
@@ -124,7 +200,7 @@ impl Handler for ExistingFileHandler {
                                     }
                                 }
                                 let file = match FileContent::load(object).await {
-                                    Ok(file) => file,
+                                    Ok(file) => vary.into_iter().fold(file, FileContent::vary),
                                     Err(err) => {
                                         println!(
                                             "File content expected but could not load: {}",
@@ -145,11 +221,22 @@ impl Handler for ExistingFileHandler {
                     None => {}
                 };
 
+                let image_type = image_type.unwrap_or(ImageFormat::PNG);
+                let transformations =
+                    match flatten_for_format(transformations, &image_type, background, Some(&vobj))
+                    {
+                        Ok(transformations) => transformations,
+                        Err(err) => {
+                            println!("Could not flatten image {}", err);
+                            return Outcome::failure(Status::InternalServerError);
+                        }
+                    };
                 let encoded_image = match read_transform_encode(
                     &object.file_path,
                     transformations,
                     quality,
-                    image_type.unwrap_or(ImageFormat::PNG),
+                    encoder_options,
+                    image_type,
                     sem,
                 )
                 .await
@@ -172,13 +259,14 @@ impl Handler for ExistingFileHandler {
                     content_type,
                     ContentEncodingValue::Identity,
                     None,
-                );
+                )
+                .map(|content| vary.into_iter().fold(content, ByteContent::vary));
 
                 Outcome::from(req, content)
             }
             None => {
                 let file = match FileContent::load(object).await {
-                    Ok(file) => file,
+                    Ok(file) => vary.into_iter().fold(file, FileContent::vary),
                     Err(err) => {
                         println!("File content expected but could not load: {}", err);
                         return Outcome::failure(Status::InternalServerError);
@@ -31,6 +31,7 @@ pub struct FileContent {
     object: Object,
     file: File,
     etag: Option<String>,
+    vary: Vec<&'static str>,
 }
 
 impl FileContent {
@@ -45,7 +46,17 @@ impl FileContent {
             None
         };
 
-        Ok(Self { file, object, etag })
+        Ok(Self {
+            file,
+            object,
+            etag,
+            vary: Vec::new(),
+        })
+    }
+    // Request headers that changed which object was chosen
+    pub fn vary(mut self, header: &'static str) -> Self {
+        self.vary.push(header);
+        self
     }
 }
 
@@ -60,8 +71,19 @@ impl<'r> Responder<'r, 'static> for FileContent {
             response.set_header(Header::new("x-content-type-options", "nosniff"));
         }
 
+        // Scripts in SVG documents may run when opened directly
+        if content_type == "image/svg+xml" {
+            response.set_header(Header::new(
+                "Content-Security-Policy",
+                "default-src 'none'; style-src 'unsafe-inline'; img-src data:; sandbox",
+            ));
+        }
+
         response.set_header(Header::new("Content-Type", content_type));
         response.set_header(Header::new("Age", "0"));
+        if !self.vary.is_empty() {
+            response.set_header(Header::new("Vary", self.vary.join(", ")));
+        }
 
         match ContentEncodingValue::from_database(&content_encoding) {
             ContentEncodingValue::Default => {}
@@ -179,6 +179,20 @@ pub fn upload_path() -> Result<PathBuf, String> {
         .map(|p| p.clone())
 }
 
+// Only for files no object refers to anymore
+pub async fn remove_upload_file(file_path: &str) -> Result<(), String> {
+    if file_path.contains('/') || file_path.contains('\\') || file_path.starts_with('.') {
+        return Err(format!("Refusing to remove {}", file_path));
+    }
+    let mut path = upload_path()?;
+    path.push(file_path);
+    tokio::fs::remove_file(&path)
+        .await
+        .map_err(|err| format!("{:?}", err))?;
+    println!("Removed file {:?}", path);
+    Ok(())
+}
+
 pub async fn write_bytes_to_file(to_path: &Path, bytes: &[u8]) -> Result<(), String> {
     let mut to_file = File::create(to_path)
         .await
@@ -14,19 +14,151 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::manifest::encode_path;
 use crate::virtual_object::{
-    find_related_objects_to_virtual_object, find_virtual_object_by_object_paths,
+    find_related_objects_matching, find_related_objects_to_virtual_object,
+    find_virtual_object_by_object_paths, resolve_alias, AliasResolution,
 };
 use diesel::sqlite::SqliteConnection;
 
+use crate::client_hints::{ClientHintValues, HINT_DPR, HINT_VIEWPORT_WIDTH, HINT_WIDTH};
 use crate::content_encoding::ContentEncodingValue;
-use crate::models::Object;
-use crate::parsing::grab_basename;
+use crate::models::{Object, VirtualObject};
+use crate::object::find_object_by_id;
+use crate::parsing::{grab_basename, strip_density_suffix, valid_dpr};
 use crate::transformations::TransformationList;
 
 // use rocket::http::ContentType;
 use rocket::request::Request;
 
+// Aspect ratios within this distance (in log space, about 2%) are the same shape
+const ASPECT_TOLERANCE: f64 = 0.02;
+
+// Formats every client supports come first
+const FORMAT_PREFERENCE: [&str; 7] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/avif",
+    "image/jxl",
+    "image/svg+xml",
+];
+
+fn is_lossless(object: &Object) -> bool {
+    match object.content_type.as_str() {
+        "image/png" | "image/gif" | "image/svg+xml" | "image/bmp" | "image/tiff" => true,
+        "image/jxl" if object.quality == Some(100) => true,
+        _ => object
+            .encoder_options
+            .as_deref()
+            .map(|options| options.split(',').any(|option| option == "ll"))
+            .unwrap_or(false),
+    }
+}
+
+// Compared in field order, lower is better
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct SelectionScore {
+    // 0 fits exactly, 1 is larger and will be scaled down,
+    // 2 is smaller and will be scaled up, 3 has unknown dimensions
+    tier: u8,
+    // 1 when the shape differs from the requested box
+    aspect_mismatch: u8,
+    // How far from the requested size, in thousandths of the scale
+    distance: i64,
+    lossless: u8,
+    format: usize,
+    id: i32,
+}
+
+// Requested dimensions are a box to fit within, either may be absent
+fn score_object(object: &Object, width: Option<i32>, height: Option<i32>) -> SelectionScore {
+    let mut score = SelectionScore {
+        tier: 3,
+        aspect_mismatch: 0,
+        distance: 0,
+        lossless: if is_lossless(object) { 0 } else { 1 },
+        format: FORMAT_PREFERENCE
+            .iter()
+            .position(|content_type| *content_type == object.content_type)
+            .unwrap_or(FORMAT_PREFERENCE.len()),
+        id: object.id,
+    };
+    let (ow, oh) = (object.width, object.height);
+    // Scale that would make the object fit the request
+    let scale = match (width, height, ow, oh) {
+        (None, None, Some(ow), Some(oh)) => {
+            // Without a request the largest is best
+            score.tier = 0;
+            score.distance = -(ow as i64 * oh as i64);
+            return score;
+        }
+        (Some(w), Some(h), Some(ow), Some(oh)) if ow > 0 && oh > 0 && w > 0 && h > 0 => {
+            let requested_aspect = w as f64 / h as f64;
+            let aspect = ow as f64 / oh as f64;
+            if (aspect / requested_aspect).ln().abs() > ASPECT_TOLERANCE {
+                score.aspect_mismatch = 1;
+            }
+            if ow <= w && oh <= h && (ow == w || oh == h) {
+                1.0
+            } else {
+                (w as f64 / ow as f64).min(h as f64 / oh as f64)
+            }
+        }
+        (Some(w), None, Some(ow), _) if ow > 0 => w as f64 / ow as f64,
+        (None, Some(h), _, Some(oh)) if oh > 0 => h as f64 / oh as f64,
+        _ => return score,
+    };
+    if scale == 1.0 {
+        score.tier = 0;
+    } else if scale < 1.0 {
+        score.tier = 1;
+        score.distance = (1000.0 / scale).round() as i64;
+    } else {
+        score.tier = 2;
+        score.distance = (1000.0 * scale).round() as i64;
+    }
+    score
+}
+
+// The object to work from when a virtual object has no primary object:
+// the highest resolution, then lossless, then an original over a derivation
+pub fn dominant_object(objects: &[Object]) -> Option<&Object> {
+    objects.iter().min_by_key(|object| {
+        let area = object.width.unwrap_or(0) as i64 * object.height.unwrap_or(0) as i64;
+        (
+            -area,
+            !is_lossless(object),
+            object.derived_object_id.is_some(),
+            object.id,
+        )
+    })
+}
+
+// Uses the primary object when set, otherwise the dominant related object
+pub fn find_primary_object(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+) -> Result<Option<Object>, String> {
+    if let Some(id) = vobj.primary_object_id {
+        return find_object_by_id(conn, id);
+    }
+    let objects = find_related_objects_to_virtual_object(conn, vobj)?;
+    let dominant = dominant_object(&objects).cloned();
+    if let Some(object) = &dominant {
+        println!("Dominant object of {} is {}", vobj.object_path, object.id);
+    }
+    Ok(dominant)
+}
+
+// Picks the same object regardless of the order objects are given in
+fn select_object(objects: &[Object], width: Option<i32>, height: Option<i32>) -> Option<&Object> {
+    objects
+        .iter()
+        .min_by_key(|object| score_object(object, width, height))
+}
+
 pub fn find_object_by_parameters(
     conn: &SqliteConnection,
     paths: &[&str],
@@ -34,7 +166,7 @@ pub fn find_object_by_parameters(
     height: Option<i32>,
     content_type: Option<&str>,
     content_encoding: Option<ContentEncodingValue>,
-) -> Result<Option<Object>, String> {
+) -> Result<Option<(VirtualObject, Object)>, String> {
     println!("Looking for virtual object by path {:?}", paths);
     println!(
         "With type {:?} and encoding {:?}",
@@ -52,107 +184,122 @@ pub fn find_object_by_parameters(
         }
     };
     println!("Found virtual object {:?}", virtual_object);
-    // TODO find only related objects that match content type
-    // TODO consider content encoding
-    let objects = find_related_objects_to_virtual_object(conn, &virtual_object)?;
-    // println!("Found objects {:?}", objects);
-    if objects.is_empty() {
-        println!("Bailing out early, objects is empty");
-        return Ok(None);
-    }
-    let same_extension: Vec<Object> = objects
-        .into_iter()
-        .filter(|o| match &content_type {
-            None => true,
-            Some(v) => o.content_type == *v,
-        })
-        .filter(|o| match &content_encoding {
-            None => true,
-            Some(v) => ContentEncodingValue::from_database(&o.content_encoding) == *v,
-        })
-        .collect();
+    let virtual_object = match resolve_alias(conn, virtual_object)? {
+        AliasResolution::Target(target) => target,
+        // The handler answers with a redirect instead
+        AliasResolution::Redirect(_, _) => return Ok(None),
+    };
+    let encodings = content_encoding
+        .as_ref()
+        .and_then(|encoding| encoding.database_values());
+    let find = |size: Option<(i32, i32)>| -> Result<Vec<Object>, String> {
+        let objects =
+            find_related_objects_matching(conn, &virtual_object, content_type, encodings, size)?;
+        // Unknown encodings cannot be listed in the query
+        Ok(objects
+            .into_iter()
+            .filter(|o| match &content_encoding {
+                Some(v) if encodings.is_none() => {
+                    ContentEncodingValue::from_database(&o.content_encoding) == *v
+                }
+                _ => true,
+            })
+            .collect())
+    };
+    // Exact sizes are checked before loading every related object
+    let exact = match width.zip(height) {
+        Some(size) => find(Some(size))?,
+        None => Vec::new(),
+    };
+    let same_extension = if exact.is_empty() { find(None)? } else { exact };
     // Bail out early
     if same_extension.is_empty() {
-        println!("No matching extension");
+        println!("No matching objects");
         return Ok(None);
     }
-    // TODO
     println!("Looking for closest {:?}, {:?}", width, height);
-    let closest = same_extension.iter().reduce(|left, right| {
-        println!(
-            "Folding left:{:?}, right:{:?}",
-            (left.id, left.width, left.height),
-            (right.id, right.width, right.height)
-        );
-        match (
-            left.width,
-            left.height,
-            right.width,
-            right.height,
-            width,
-            height,
-        ) {
-            // ---------EXACT-MATCHES--------------------
-            // Keep left if exact match
-            (Some(wl), Some(hl), _, _, Some(w), Some(h)) if wl == w && hl == h => left,
-            // Keep right if exact match
-            (_, _, Some(wr), Some(hr), Some(w), Some(h)) if wr == w && hr == h => right,
-            // Keep left if width matches exactly and height is smaller than width
-            (Some(wl), _, _, _, Some(w), Some(h)) if wl == w && h <= w => left,
-            // Keep left if height matches exactly and width is smaller than height
-            (_, Some(hl), _, _, Some(w), Some(h)) if hl == h && w <= h => left,
-            // Keep right if width matches exactly and height is smaller than width
-            (_, _, Some(wr), _, Some(w), Some(h)) if wr == w && h <= w => right,
-            // Keep right if height matches exactly and width is smaller than height
-            (_, _, _, Some(hr), Some(w), Some(h)) if hr == h && w <= h => right,
-            // Keep right if width matches exactly
-            (_, _, Some(wr), _, Some(w), None) if wr == w => right,
-            // Keep right if height matches exactly
-            (_, _, _, Some(hr), None, Some(h)) if hr == h => right,
-
-            // ------------------------------------------
-            // Bias right if smaller than left but greater than desired width
-            (Some(wl), _, Some(wr), _, Some(w), Some(h))
-                if wr >= w && (wr < wl || wl < w) && h <= w =>
-            {
-                right
-            }
-            (Some(wl), _, Some(wr), _, Some(w), None) if wr >= w && (wr < wl || wl < w) => right,
-            // Bias right if smaller than left but greater than desired height
-            (_, Some(hl), _, Some(hr), Some(w), Some(h))
-                if hr >= h && (hr < hl || hl < h) && w <= h =>
-            {
-                right
-            }
-            (_, Some(hl), _, Some(hr), None, Some(h)) if hr >= h && (hr < hl || hl < h) => right,
-            // Bias right if width is a greater size
-            (None, _, Some(wr), _, Some(w), Some(h)) if wr >= w && h <= w => right,
-            (None, _, Some(wr), _, Some(w), None) if wr >= w => right,
-            // Bias right if height is a greater size
-            (_, None, _, Some(hr), Some(w), Some(h)) if hr >= h && w <= h => right,
-            (_, None, _, Some(hr), None, Some(h)) if hr >= h => right,
-            // Keep left if right is not greater or equal to
-            _ => left,
-        }
-    });
-    println!("Found closest {:?}", closest);
-    Ok(closest.cloned())
+    let closest = select_object(&same_extension, width, height);
+    println!(
+        "Found closest {:?}",
+        closest.map(|o| (o.id, o.width, o.height))
+    );
+    Ok(closest.cloned().map(|object| (virtual_object, object)))
 }
 
 pub struct ExistingFileRequestQuery {
     raw_path: String,
-    path_ranges: Vec<std::ops::Range<usize>>,
+    first_segment_is_dimensions: bool,
+    query_string: Option<String>,
+    paths: Vec<String>,
     width: Option<i32>,
     height: Option<i32>,
     content_type: Option<String>,
     content_encoding: Option<ContentEncodingValue>,
     transformations: Option<TransformationList>,
+    dpr: Option<f32>,
+    save_data: bool,
+    // Client hint headers that the response depends on
+    vary: Vec<&'static str>,
 }
 
 impl ExistingFileRequestQuery {
     pub fn transformations(&self) -> Option<TransformationList> {
         self.transformations.clone()
     }
+    pub fn dpr(&self) -> Option<f32> {
+        self.dpr
+    }
+    pub fn save_data(&self) -> bool {
+        self.save_data
+    }
+    pub fn vary(&self) -> Vec<&'static str> {
+        self.vary.clone()
+    }
+}
+
+// Paths to look for, with and without extensions and the dimension prefix
+fn candidate_paths(raw_path: &str, first_segment_is_dimensions: bool) -> Vec<String> {
+    let mut skip_first = 0..raw_path.len();
+    if first_segment_is_dimensions {
+        match raw_path.find('/') {
+            None => {}
+            Some(slash_index) => {
+                skip_first = slash_index + 1..raw_path.len();
+                let slice = &raw_path[slash_index + 1..raw_path.len()];
+                println!("Without path params: {}", slice);
+            }
+        }
+    }
+
+    let parsed_path = grab_basename(raw_path);
+
+    let first_extension = parsed_path
+        .content_type_ext_range
+        .clone()
+        .map(|r| skip_first.start..r.start - 1);
+    let second_extension = parsed_path
+        .content_encoding_ext_range
+        .clone()
+        .map(|r| skip_first.start..r.start - 1);
+
+    // Most specific first: the exact path, without the encoding,
+    // then without the content type as well
+    let mut path_ranges = Vec::with_capacity(3);
+    path_ranges.push(skip_first);
+    if let Some(range) = second_extension {
+        path_ranges.push(range);
+    }
+    if let Some(range) = first_extension {
+        path_ranges.push(range);
+    }
+    let mut paths: Vec<String> = Vec::with_capacity(path_ranges.len());
+    for range in path_ranges {
+        let path = raw_path[range].to_string();
+        if !path.is_empty() && !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
 }
 
 pub fn parse_existing_file_request(req: &Request<'_>) -> ExistingFileRequestQuery {
@@ -208,30 +355,7 @@ pub fn parse_existing_file_request(req: &Request<'_>) -> ExistingFileRequestQuer
     // TODO don't use path, piece it out so .tar.gz => tar.gz is the extension
     // and that the content_type is tar and the content_encoding is gzip
 
-    let mut skip_first = 0..raw_path.len();
-    let mut include_full = true;
-    if first_segment_is_dimensions {
-        match raw_path.find('/') {
-            None => {}
-            Some(slash_index) => {
-                skip_first = slash_index + 1..raw_path.len();
-                let slice = &raw_path[slash_index + 1..raw_path.len()];
-                println!("Without path params: {}", slice);
-                include_full = false;
-            }
-        }
-    }
-
     let parsed_path = grab_basename(&raw_path);
-
-    let first_extension = parsed_path
-        .content_type_ext_range
-        .clone()
-        .map(|r| skip_first.start..r.start - 1);
-    let second_extension = parsed_path
-        .content_encoding_ext_range
-        .clone()
-        .map(|r| skip_first.start..r.start - 1);
     let content_type = parsed_path
         .find_content_type()
         .map(|(top, sub)| format!("{}/{}", top, sub));
@@ -240,20 +364,41 @@ pub fn parse_existing_file_request(req: &Request<'_>) -> ExistingFileRequestQuer
     // println!("Encoding: {:?}, Extension: {:?}", parsed_path.content_encoding_ext_range.map(|r| &raw_path[r]), parsed_path.content_type_ext_range.map(|r| &raw_path[r]));
     // TODO convert to content type combo and encoding
 
-    let mut path_ranges = Vec::with_capacity(3);
-    if skip_first.start > 0 {
-        path_ranges.push(skip_first);
+    // photo@2x.jpg is photo.jpg at twice the size, the query parameter wins
+    let density = strip_density_suffix(&raw_path);
+    let hints = ClientHintValues::from_request(req);
+    let mut vary = Vec::new();
+    let mut dpr = req
+        .query_value::<f32>("dpr")
+        .transpose()
+        .unwrap_or(None)
+        .and_then(valid_dpr)
+        .or_else(|| density.as_ref().map(|(_, dpr)| *dpr));
+    if dpr.is_none() {
+        vary.push(HINT_DPR);
+        dpr = hints.dpr;
     }
-    if let Some(range) = first_extension {
-        path_ranges.push(range);
+    let mut paths = candidate_paths(&raw_path, first_segment_is_dimensions);
+    if let Some((density_path, _)) = &density {
+        for path in candidate_paths(density_path, first_segment_is_dimensions) {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
     }
-    if let Some(range) = second_extension {
-        path_ranges.push(range);
+    if let Some(dpr) = dpr {
+        println!("Scaling dimensions by {}", dpr);
+        width = width.map(|w| (w as f32 * dpr).round() as i32);
+        height = height.map(|h| (h as f32 * dpr).round() as i32);
     }
-
-    // Raw path is added last
-    if include_full {
-        path_ranges.push(0..raw_path.len());
+    // Hinted widths are already in device pixels
+    if width.is_none() && height.is_none() {
+        vary.push(HINT_WIDTH);
+        vary.push(HINT_VIEWPORT_WIDTH);
+        width = hints.device_width();
+        if let Some(w) = width {
+            println!("Width hinted as {}", w);
+        }
     }
 
     let transformations = req
@@ -263,31 +408,199 @@ pub fn parse_existing_file_request(req: &Request<'_>) -> ExistingFileRequestQuer
 
     ExistingFileRequestQuery {
         raw_path,
-        path_ranges,
+        first_segment_is_dimensions,
+        query_string: req.uri().query().map(|query| query.as_str().to_string()),
+        paths,
         width,
         height,
         content_type,
         content_encoding,
         transformations,
+        dpr,
+        save_data: hints.save_data,
+        vary,
     }
 }
 
 pub fn search_existing_file_query(
     conn: &SqliteConnection,
-    query: ExistingFileRequestQuery,
-) -> Result<Option<Object>, String> {
-    let paths: Vec<&str> = query
-        .path_ranges
-        .iter()
-        .map(|range| &query.raw_path[range.clone()])
-        .collect();
-    let content_type = query.content_type;
+    query: &ExistingFileRequestQuery,
+) -> Result<Option<(VirtualObject, Object)>, String> {
+    let paths: Vec<&str> = query.paths.iter().map(|path| path.as_str()).collect();
     find_object_by_parameters(
         conn,
         &paths,
         query.width,
         query.height,
-        content_type.as_deref(),
-        query.content_encoding,
+        query.content_type.as_deref(),
+        query.content_encoding.clone(),
     )
 }
+
+// Swaps the matched path for the target, keeping the dimensions,
+// any extensions after the match and the query string
+fn redirect_location(
+    raw_path: &str,
+    first_segment_is_dimensions: bool,
+    matched: &str,
+    target: &str,
+    query_string: Option<&str>,
+) -> String {
+    let (dimensions, rest) = match raw_path.find('/') {
+        Some(slash_index) if first_segment_is_dimensions => raw_path.split_at(slash_index + 1),
+        _ => ("", raw_path),
+    };
+    let path = match rest.strip_prefix(matched) {
+        Some(remainder) => format!("{}{}{}", dimensions, target, remainder),
+        None => format!("{}{}", dimensions, target),
+    };
+    match query_string {
+        Some(query) if !query.is_empty() => format!("/{}?{}", encode_path(&path), query),
+        _ => format!("/{}", encode_path(&path)),
+    }
+}
+
+// The status and location when the requested path is a redirecting alias
+pub fn find_alias_redirect(
+    conn: &SqliteConnection,
+    query: &ExistingFileRequestQuery,
+) -> Result<Option<(i32, String)>, String> {
+    let paths: Vec<&str> = query.paths.iter().map(|path| path.as_str()).collect();
+    let virtual_object = match find_virtual_object_by_object_paths(conn, &paths)? {
+        Some(virtual_object) => virtual_object,
+        None => return Ok(None),
+    };
+    let matched = virtual_object.object_path.clone();
+    match resolve_alias(conn, virtual_object)? {
+        AliasResolution::Target(_) => Ok(None),
+        AliasResolution::Redirect(status, target) => Ok(Some((
+            status,
+            redirect_location(
+                &query.raw_path,
+                query.first_segment_is_dimensions,
+                &matched,
+                &target.object_path,
+                query.query_string.as_deref(),
+            ),
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_keep_the_rest_of_the_request() {
+        assert_eq!(
+            redirect_location("old.jpg", false, "old", "new", None),
+            "/new.jpg"
+        );
+        assert_eq!(
+            redirect_location(
+                "r100x100/a/old.webp",
+                true,
+                "a/old",
+                "b/new",
+                Some("t=blur(2)")
+            ),
+            "/r100x100/b/new.webp?t=blur(2)"
+        );
+        assert_eq!(
+            redirect_location("old@2x.png", false, "old.png", "new photo", Some("")),
+            "/new%20photo"
+        );
+    }
+
+    #[test]
+    fn selection_is_scored() {
+        let objects = vec![
+            Object::sample(1, "image/jpeg", 400, 300),
+            Object::sample(2, "image/jpeg", 800, 600),
+            Object::sample(3, "image/jpeg", 1600, 1200),
+            Object::sample(4, "image/png", 800, 600),
+            Object::sample(5, "image/jpeg", 600, 600),
+            Object::sample(6, "image/webp", 3200, 2400),
+        ];
+        let cases: Vec<(Option<i32>, Option<i32>, i32)> = vec![
+            // Exact matches prefer lossless
+            (Some(800), Some(600), 4),
+            (Some(800), None, 4),
+            (None, Some(600), 4),
+            // Fits the box on one side
+            (Some(800), Some(800), 4),
+            // Exact fit of the same shape
+            (Some(600), Some(600), 5),
+            // Closest larger object is scaled down
+            (Some(1000), None, 3),
+            (Some(700), Some(525), 4),
+            // Nothing large enough, the largest is scaled up
+            (Some(4000), None, 6),
+            // Smaller than everything
+            (Some(200), Some(150), 1),
+            // Nothing requested gives the largest
+            (None, None, 6),
+        ];
+        for (width, height, expected) in cases {
+            let selected = select_object(&objects, width, height).map(|o| o.id);
+            assert_eq!(Some(expected), selected, "for {:?}x{:?}", width, height);
+            let mut reversed = objects.clone();
+            reversed.reverse();
+            let selected = select_object(&reversed, width, height).map(|o| o.id);
+            assert_eq!(
+                Some(expected),
+                selected,
+                "reversed {:?}x{:?}",
+                width,
+                height
+            );
+        }
+    }
+
+    #[test]
+    fn dominant_object_is_largest_then_lossless_then_original() {
+        let mut derived = Object::sample(1, "image/png", 1600, 1200);
+        derived.derived_object_id = Some(9);
+        let objects = vec![
+            Object::sample(2, "image/jpeg", 800, 600),
+            derived.clone(),
+            Object::sample(3, "image/jpeg", 1600, 1200),
+        ];
+        assert_eq!(Some(1), dominant_object(&objects).map(|o| o.id));
+        let objects = vec![derived, Object::sample(4, "image/png", 1600, 1200)];
+        assert_eq!(Some(4), dominant_object(&objects).map(|o| o.id));
+        assert!(dominant_object(&[]).is_none());
+    }
+
+    #[test]
+    fn candidate_paths_are_most_specific_first() {
+        assert_eq!(
+            vec!["a.tar.gz", "a.tar", "a"],
+            candidate_paths("a.tar.gz", false)
+        );
+        assert_eq!(
+            vec!["photo.jpg", "photo"],
+            candidate_paths("r100x100/photo.jpg", true)
+        );
+        assert_eq!(vec!["photo"], candidate_paths("photo", false));
+    }
+
+    #[test]
+    fn lossless_variants_are_preferred() {
+        let mut lossless = Object::sample(2, "image/webp", 800, 600);
+        lossless.encoder_options = Some("ll,e4".to_string());
+        let objects = vec![Object::sample(1, "image/webp", 800, 600), lossless];
+        assert_eq!(
+            Some(2),
+            select_object(&objects, Some(800), Some(600)).map(|o| o.id)
+        );
+        let mut no_dimensions = Object::sample(3, "image/png", 0, 0);
+        no_dimensions.width = None;
+        no_dimensions.height = None;
+        let objects = vec![no_dimensions, Object::sample(4, "image/jpeg", 10, 10)];
+        assert_eq!(
+            Some(4),
+            select_object(&objects, Some(800), None).map(|o| o.id)
+        );
+    }
+}
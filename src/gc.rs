@@ -0,0 +1,413 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::serde::Serialize;
+use rocket::{Orbit, Rocket};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+use crate::file_things::upload_path;
+use crate::models::Object;
+use crate::object::{delete_object_rows, file_path_is_referenced};
+use crate::sqlite::Pool;
+use crate::virtual_object::{delete_virtual_object_rows, is_content_addressed};
+
+// Objects and files younger than this may belong to an upload still in progress
+pub const GC_GRACE_SECONDS: u64 = 3600;
+const GC_BATCH_SIZE: usize = 500;
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GarbageReport {
+    pub dry_run: bool,
+    // File paths of objects nothing refers to
+    pub objects: Vec<String>,
+    // Files in the upload directory without an object
+    pub files: Vec<String>,
+    // Hash named virtual objects that only served those objects
+    pub virtual_objects: Vec<String>,
+    pub bytes: i64,
+}
+
+// Objects in use are related to or primary for a virtual object,
+// objects they were derived from are kept so they can be derived again
+pub fn find_garbage_ids(
+    objects: &[(i32, Option<i32>, i64)],
+    referenced: &HashSet<i32>,
+    cutoff: i64,
+) -> HashSet<i32> {
+    let parents: HashMap<i32, Option<i32>> = objects
+        .iter()
+        .map(|(id, derived_from, _)| (*id, *derived_from))
+        .collect();
+    let mut live: HashSet<i32> = HashSet::new();
+    for id in referenced {
+        let mut current = Some(*id);
+        while let Some(id) = current {
+            if !live.insert(id) {
+                break;
+            }
+            current = parents.get(&id).copied().flatten();
+        }
+    }
+    objects
+        .iter()
+        .filter(|(id, _, modified)| !live.contains(id) && *modified < cutoff)
+        .map(|(id, _, _)| *id)
+        .collect()
+}
+
+// The virtual object id, its path, and the object it only exists for
+type Placeholder = (i32, String, i32);
+
+// Derivations are also saved under their content hash with the virtual object
+// they were derived from, those copies are not a reason to keep them
+fn find_placeholders(conn: &SqliteConnection) -> QueryResult<Vec<Placeholder>> {
+    use crate::schema::{object, virtual_object};
+    let candidates: Vec<(i32, String, i32, String)> = virtual_object::table
+        .inner_join(object::table)
+        .filter(virtual_object::derived_virtual_object_id.is_not_null())
+        .select((
+            virtual_object::id,
+            virtual_object::object_path,
+            object::id,
+            object::content_hash,
+        ))
+        .load(conn)?;
+    Ok(candidates
+        .into_iter()
+        .filter(|(_, path, _, content_hash)| is_content_addressed(path, content_hash))
+        .map(|(id, path, object_id, _)| (id, path, object_id))
+        .collect())
+}
+
+// Primary objects of virtual objects other than placeholders
+pub fn live_primaries(primaries: &[(i32, Option<i32>)], placeholders: &HashSet<i32>) -> Vec<i32> {
+    primaries
+        .iter()
+        .filter(|(id, _)| !placeholders.contains(id))
+        .filter_map(|(_, object_id)| *object_id)
+        .collect()
+}
+
+// Retained revisions keep their objects alive too
+fn find_referenced_ids(
+    conn: &SqliteConnection,
+    placeholders: &HashSet<i32>,
+) -> QueryResult<HashSet<i32>> {
+    use crate::schema::{
+        virtual_object, virtual_object_relation, virtual_object_revision,
+        virtual_object_revision_object,
+    };
+    let related: Vec<i32> = virtual_object_relation::table
+        .select(virtual_object_relation::object_id)
+        .load(conn)?;
+    let primaries: Vec<(i32, Option<i32>)> = virtual_object::table
+        .select((virtual_object::id, virtual_object::primary_object_id))
+        .filter(virtual_object::primary_object_id.is_not_null())
+        .load(conn)?;
+    let revised: Vec<i32> = virtual_object_revision_object::table
+        .select(virtual_object_revision_object::object_id)
+        .load(conn)?;
+    let revised_primaries: Vec<Option<i32>> = virtual_object_revision::table
+        .select(virtual_object_revision::primary_object_id)
+        .filter(virtual_object_revision::primary_object_id.is_not_null())
+        .load(conn)?;
+    Ok(related
+        .into_iter()
+        .chain(live_primaries(&primaries, placeholders))
+        .chain(revised)
+        .chain(revised_primaries.into_iter().flatten())
+        .collect())
+}
+
+// Every object, which of them are garbage, and the placeholders of that garbage
+fn scan_garbage(
+    conn: &SqliteConnection,
+    cutoff: i64,
+) -> QueryResult<(Vec<Object>, HashSet<i32>, Vec<Placeholder>)> {
+    use crate::schema::object;
+    let objects: Vec<Object> = object::table.load(conn)?;
+    let summary: Vec<(i32, Option<i32>, i64)> = objects
+        .iter()
+        .map(|o| (o.id, o.derived_object_id, o.modified))
+        .collect();
+    let placeholders = find_placeholders(conn)?;
+    let placeholder_ids: HashSet<i32> = placeholders.iter().map(|(id, _, _)| *id).collect();
+    let referenced = find_referenced_ids(conn, &placeholder_ids)?;
+    let garbage = find_garbage_ids(&summary, &referenced, cutoff);
+    let placeholders = placeholders
+        .into_iter()
+        .filter(|(_, _, object_id)| garbage.contains(object_id))
+        .collect();
+    Ok((objects, garbage, placeholders))
+}
+
+fn seconds_since_epoch(time: SystemTime) -> Result<u64, String> {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .map_err(|err| format!("{}", err))
+}
+
+fn file_modified(metadata: &std::fs::Metadata) -> Result<u64, String> {
+    metadata
+        .modified()
+        .map_err(|err| format!("{}", err))
+        .and_then(seconds_since_epoch)
+}
+
+// Reports what nothing refers to, and removes it unless this is a dry run
+pub fn blocking_collect_garbage(
+    conn: &SqliteConnection,
+    dry_run: bool,
+) -> Result<GarbageReport, String> {
+    let cutoff = seconds_since_epoch(SystemTime::now())?.saturating_sub(GC_GRACE_SECONDS);
+    let (objects, mut garbage, mut placeholders) =
+        scan_garbage(conn, cutoff as i64).map_err(|err| format!("{}", err))?;
+    let kept_files: HashSet<String> = objects
+        .iter()
+        .filter(|o| !garbage.contains(&o.id))
+        .map(|o| o.file_path.clone())
+        .collect();
+    let garbage_files: HashSet<String> = objects
+        .iter()
+        .filter(|o| garbage.contains(&o.id))
+        .map(|o| o.file_path.clone())
+        .collect();
+
+    let upload_dir = upload_path()?;
+    let mut orphans = Vec::new();
+    let entries = std::fs::read_dir(&upload_dir).map_err(|err| format!("{}", err))?;
+    for entry in entries {
+        let entry = entry.map_err(|err| format!("{}", err))?;
+        let metadata = entry.metadata().map_err(|err| format!("{}", err))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !metadata.is_file() || kept_files.contains(&name) || garbage_files.contains(&name) {
+            continue;
+        }
+        if file_modified(&metadata)? < cutoff {
+            orphans.push((name, metadata.len() as i64));
+        }
+    }
+
+    if !dry_run {
+        // Something may have referred to the garbage since it was found, holding
+        // the write lock keeps that out while it is found again and deleted
+        let found: Vec<i32> = garbage.iter().copied().collect();
+        let (still_garbage, still_placeholders) = conn
+            .immediate_transaction::<_, diesel::result::Error, _>(|| {
+                let (_, still_garbage, still_placeholders) = scan_garbage(conn, cutoff as i64)?;
+                let ids: Vec<i32> = found
+                    .iter()
+                    .copied()
+                    .filter(|id| still_garbage.contains(id))
+                    .collect();
+                let still_placeholders: Vec<Placeholder> = still_placeholders
+                    .into_iter()
+                    .filter(|(_, _, object_id)| ids.contains(object_id))
+                    .collect();
+                let vobj_ids: Vec<i32> = still_placeholders.iter().map(|(id, _, _)| *id).collect();
+                // SQLite limits how many values a query can bind
+                for chunk in vobj_ids.chunks(GC_BATCH_SIZE) {
+                    delete_virtual_object_rows(conn, chunk)?;
+                }
+                for chunk in ids.chunks(GC_BATCH_SIZE) {
+                    delete_object_rows(conn, chunk)?;
+                }
+                Ok((
+                    ids.into_iter().collect::<HashSet<i32>>(),
+                    still_placeholders,
+                ))
+            })
+            .map_err(|err| format!("{}", err))?;
+        garbage = still_garbage;
+        placeholders = still_placeholders;
+        println!("Deleted objects {:?}", garbage);
+
+        for object in objects.iter().filter(|o| garbage.contains(&o.id)) {
+            // Another object may have been stored with the same content since
+            if file_path_is_referenced(conn, &object.file_path)? {
+                continue;
+            }
+            let path = upload_dir.join(&object.file_path);
+            if let Err(err) = std::fs::remove_file(&path) {
+                println!("Could not remove {:?}: {}", path, err);
+            }
+        }
+        let mut removed = Vec::with_capacity(orphans.len());
+        for (name, length) in orphans {
+            let path = upload_dir.join(&name);
+            // An upload in progress may have written or claimed the file since
+            let unchanged = match std::fs::metadata(&path) {
+                Ok(metadata) => file_modified(&metadata)? < cutoff,
+                Err(_) => false,
+            };
+            if !unchanged || file_path_is_referenced(conn, &name)? {
+                continue;
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => removed.push((name, length)),
+                Err(err) => println!("Could not remove {:?}: {}", path, err),
+            }
+        }
+        orphans = removed;
+    }
+
+    let mut report = GarbageReport {
+        dry_run,
+        ..Default::default()
+    };
+    for object in objects.into_iter().filter(|o| garbage.contains(&o.id)) {
+        report.bytes += object.length;
+        report.objects.push(object.file_path);
+    }
+    for (name, length) in orphans {
+        report.bytes += length;
+        report.files.push(name);
+    }
+    report.virtual_objects = placeholders.into_iter().map(|(_, path, _)| path).collect();
+    report.objects.sort();
+    report.files.sort();
+    report.virtual_objects.sort();
+    println!(
+        "Found {} unreferenced objects, {} placeholders and {} orphaned files, {} bytes",
+        report.objects.len(),
+        report.virtual_objects.len(),
+        report.files.len(),
+        report.bytes
+    );
+    Ok(report)
+}
+
+// Runs garbage collection on an interval once the server has started
+pub struct GarbageCollector {
+    interval: Duration,
+    dry_run: bool,
+}
+
+impl GarbageCollector {
+    pub fn new(interval: Duration, dry_run: bool) -> Self {
+        Self { interval, dry_run }
+    }
+
+    // GC_INTERVAL_SECONDS enables it, GC_DRY_RUN only reports
+    pub fn from_env() -> Option<Self> {
+        let seconds = std::env::var("GC_INTERVAL_SECONDS")
+            .ok()?
+            .parse::<u64>()
+            .ok()
+            .filter(|seconds| *seconds > 0)?;
+        let dry_run = std::env::var("GC_DRY_RUN")
+            .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        Some(Self::new(Duration::from_secs(seconds), dry_run))
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for GarbageCollector {
+    fn info(&self) -> Info {
+        Info {
+            name: "Collects unreferenced objects and files",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let pool = match rocket.state::<Pool>() {
+            Some(pool) => pool.clone(),
+            None => {
+                println!("Garbage collection needs a connection pool");
+                return;
+            }
+        };
+        let interval = self.interval;
+        let dry_run = self.dry_run;
+        println!("Collecting garbage every {:?}", interval);
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(interval);
+            // The first tick is immediate, wait a full interval after starting
+            timer.tick().await;
+            loop {
+                timer.tick().await;
+                let pool = pool.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    let conn = pool.get().map_err(|e| format!("{}", e))?;
+                    blocking_collect_garbage(&conn, dry_run)
+                })
+                .await;
+                match result {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(err)) => println!("Garbage collection failed: {}", err),
+                    Err(err) => println!("Garbage collection panicked: {}", err),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ancestors_of_referenced_objects_are_kept() {
+        let objects = vec![
+            // An original, a derivation of it, and a derivation of that
+            (1, None, 0),
+            (2, Some(1), 0),
+            (3, Some(2), 0),
+            // Unreferenced original with a derivation
+            (4, None, 0),
+            (5, Some(4), 0),
+            // Too new to collect
+            (6, None, 100),
+        ];
+        let referenced: HashSet<i32> = [3].into_iter().collect();
+        let mut garbage: Vec<i32> = find_garbage_ids(&objects, &referenced, 50)
+            .into_iter()
+            .collect();
+        garbage.sort_unstable();
+        assert_eq!(vec![4, 5], garbage);
+    }
+
+    #[test]
+    fn superseded_derivations_are_collected() {
+        let objects = vec![
+            // An original, what was derived from it before and after it changed
+            (1, None, 0),
+            (2, Some(1), 0),
+            (3, Some(1), 0),
+        ];
+        let primaries = vec![
+            // The original, its thumbnail, and the hash named copy of the old one
+            (10, Some(1)),
+            (12, Some(3)),
+            (13, Some(2)),
+        ];
+        let placeholders: HashSet<i32> = [13].into_iter().collect();
+        let referenced: HashSet<i32> = live_primaries(&primaries, &placeholders)
+            .into_iter()
+            .collect();
+        let garbage: Vec<i32> = find_garbage_ids(&objects, &referenced, 50)
+            .into_iter()
+            .collect();
+        assert_eq!(vec![2], garbage);
+    }
+}
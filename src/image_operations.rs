@@ -1,15 +1,26 @@
 use image::codecs::avif::AvifEncoder;
-use image::codecs::gif::GifEncoder;
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::ico::{IcoEncoder, IcoFrame};
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::codecs::qoi::QoiEncoder;
 use image::imageops::{blur, crop, overlay, resize, FilterType};
 use image::io::Reader as ImageReader;
-use image::{ColorType, ImageBuffer, ImageOutputFormat, Rgba, RgbaImage};
+use image::{
+    AnimationDecoder, ColorType, Delay, Frame, ImageBuffer, ImageDecoder, ImageEncoder,
+    ImageOutputFormat, Rgba, RgbaImage,
+};
 use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::path::PathBuf;
 use std::str::FromStr;
 use tokio::fs::File;
 use tokio::sync::{Semaphore, SemaphorePermit};
 
+use crate::auto_format::{analyze_image, choose_format, AcceptedFormats};
+use crate::encoder_options::{ChromaSubsampling, EncoderOptions, MAX_EFFORT, MAX_PALETTE};
 use crate::file_things::upload_path;
+use crate::palette::blocking_quantize;
+use crate::quality::{ssim, AutoQuality, Quality};
+use crate::svg::blocking_svg_rasterize;
 use crate::transformations::{Transformation, TransformationList};
 
 #[allow(clippy::upper_case_acronyms, dead_code)]
@@ -20,6 +31,15 @@ pub enum ImageFormat {
     GIF,
     AVIF,
     WEBP,
+    JXL,
+    TIFF,
+    BMP,
+    ICO,
+    QOI,
+    // Only readable, SVG is rasterized before encoding
+    SVG,
+    // Chosen from the image and what the client accepts when encoding
+    AUTO(AcceptedFormats),
     UNKNOWN,
 }
 
@@ -31,12 +51,22 @@ impl ImageFormat {
             Self::GIF => Ok("gif"),
             Self::AVIF => Ok("avif"),
             Self::WEBP => Ok("webp"),
+            Self::JXL => Ok("jxl"),
+            Self::TIFF => Ok("tiff"),
+            Self::BMP => Ok("bmp"),
+            Self::ICO => Ok("vnd.microsoft.icon"),
+            Self::QOI => Ok("qoi"),
+            Self::SVG => Ok("svg+xml"),
+            Self::AUTO(_) => Err("Automatic type is not known yet".to_string()),
             Self::UNKNOWN => Err("Unknown type".to_string()),
         }
     }
     pub fn content_type(&self) -> Result<(&'static str, &'static str), String> {
         self.to_str().map(|sub| ("image", sub))
     }
+    pub fn has_alpha(&self) -> bool {
+        !matches!(self, Self::JPEG)
+    }
     #[allow(dead_code)]
     pub fn to_extension(&self) -> Result<&'static str, String> {
         match self {
@@ -45,6 +75,13 @@ impl ImageFormat {
             Self::GIF => Ok("gif"),
             Self::AVIF => Ok("avif"),
             Self::WEBP => Ok("webp"),
+            Self::JXL => Ok("jxl"),
+            Self::TIFF => Ok("tif"),
+            Self::BMP => Ok("bmp"),
+            Self::ICO => Ok("ico"),
+            Self::QOI => Ok("qoi"),
+            Self::SVG => Ok("svg"),
+            Self::AUTO(_) => Err("Automatic type is not known yet".to_string()),
             Self::UNKNOWN => Err("Unknown type".to_string()),
         }
     }
@@ -69,31 +106,88 @@ impl FromStr for ImageFormat {
             "image/gif" => Ok(Self::GIF),
             "image/avif" => Ok(Self::AVIF),
             "image/webp" => Ok(Self::WEBP),
+            "image/jxl" => Ok(Self::JXL),
+            "image/tiff" => Ok(Self::TIFF),
+            "image/bmp" => Ok(Self::BMP),
+            "image/vnd.microsoft.icon" => Ok(Self::ICO),
+            "image/x-icon" => Ok(Self::ICO),
+            "image/qoi" => Ok(Self::QOI),
+            "image/x-qoi" => Ok(Self::QOI),
+            "image/svg+xml" => Ok(Self::SVG),
             "png" => Ok(Self::PNG),
             "jpeg" => Ok(Self::JPEG),
             "jpg" => Ok(Self::JPEG),
             "gif" => Ok(Self::GIF),
             "avif" => Ok(Self::AVIF),
             "webp" => Ok(Self::WEBP),
+            "jxl" => Ok(Self::JXL),
+            "tiff" => Ok(Self::TIFF),
+            "tif" => Ok(Self::TIFF),
+            "bmp" => Ok(Self::BMP),
+            "vnd.microsoft.icon" => Ok(Self::ICO),
+            "x-icon" => Ok(Self::ICO),
+            "ico" => Ok(Self::ICO),
+            "qoi" => Ok(Self::QOI),
+            "x-qoi" => Ok(Self::QOI),
+            "svg+xml" => Ok(Self::SVG),
+            "svg" => Ok(Self::SVG),
+            "auto" => Ok(Self::AUTO(AcceptedFormats::all())),
             _ => Err(format!("Unrecognized type {}", s)),
         }
     }
 }
 
+#[derive(Clone)]
+pub struct AnimationFrame {
+    pub image: RgbaImage,
+    // Milliseconds this frame is displayed for
+    pub delay: u32,
+}
+
+#[derive(Clone)]
+pub struct ImageFrames {
+    // Still images have exactly one frame
+    frames: Vec<AnimationFrame>,
+    repeat: Repeat,
+}
+
+impl ImageFrames {
+    pub fn still(image: RgbaImage) -> Self {
+        Self {
+            frames: vec![AnimationFrame { image, delay: 0 }],
+            repeat: Repeat::Infinite,
+        }
+    }
+    pub fn first(&self) -> &RgbaImage {
+        // There is always at least one frame
+        &self.frames[0].image
+    }
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+    fn into_first(self) -> RgbaImage {
+        self.frames
+            .into_iter()
+            .next()
+            .map(|frame| frame.image)
+            .unwrap_or_default()
+    }
+}
+
 pub struct LimitedImage<'a> {
-    image: RgbaImage,
+    image: ImageFrames,
     permit: SemaphorePermit<'a>,
 }
 
 impl LimitedImage<'_> {
     pub fn width(&self) -> u32 {
-        self.image.width()
+        self.image.first().width()
     }
     pub fn height(&self) -> u32 {
-        self.image.height()
+        self.image.first().height()
     }
     pub fn rgba_vec(&self) -> Vec<u8> {
-        self.image.to_vec()
+        self.image.first().to_vec()
     }
 }
 
@@ -112,6 +206,15 @@ impl ImageSemaphore {
 pub async fn open_image<'a>(
     input_path: &str,
     sem: &'a ImageSemaphore,
+) -> Result<LimitedImage<'a>, String> {
+    open_image_sized(input_path, None, sem).await
+}
+
+// Vector images are rasterized to fit within the size hint when there is one
+pub async fn open_image_sized<'a>(
+    input_path: &str,
+    size_hint: Option<(u32, u32)>,
+    sem: &'a ImageSemaphore,
 ) -> Result<LimitedImage<'a>, String> {
     let permit = sem
         .semaphore
@@ -121,55 +224,86 @@ pub async fn open_image<'a>(
     let mut path = upload_path()?;
     path.push(input_path);
     let img = if input_path.ends_with(".webp") {
-        let data = {
-            use tokio::io::AsyncReadExt;
-            let mut f = File::open(path).await.map_err(|e| format!("{}", e))?;
-            let mut data = Vec::new();
-            f.read_to_end(&mut data)
-                .await
-                .map_err(|e| format!("{}", e))?;
-            println!("Read WebP data {} bytes", data.len());
-            data
-        };
+        let data = read_image_data(path).await?;
+        println!("Read WebP data {} bytes", data.len());
 
-        let decoder = webp::Decoder::new(&data);
-        match decoder.decode() {
-            None => {
-                return Err("Could not decode webp".to_string());
-            }
-            Some(webp_image) => {
-                let internal_img = webp_image.to_image().into_rgba8();
-                let new_img = RgbaImage::from_raw(
-                    internal_img.width(),
-                    internal_img.height(),
-                    internal_img.to_vec(),
-                );
-                match new_img {
-                    Some(img) => {
-                        println!("Parsed webp image!");
-                        img
-                    }
-                    None => {
-                        return Err("Could not copy webp data".to_string());
+        if is_animated_webp(&data) {
+            tokio::task::spawn_blocking(move || {
+                blocking_animated_webp_open(&data, FrameBudget::default())
+            })
+            .await
+            .map_err(|e| format!("{}", e))??
+        } else {
+            let decoder = webp::Decoder::new(&data);
+            match decoder.decode() {
+                None => {
+                    return Err("Could not decode webp".to_string());
+                }
+                Some(webp_image) => {
+                    let internal_img = webp_image.to_image().into_rgba8();
+                    let new_img = RgbaImage::from_raw(
+                        internal_img.width(),
+                        internal_img.height(),
+                        internal_img.to_vec(),
+                    );
+                    match new_img {
+                        Some(img) => {
+                            println!("Parsed webp image!");
+                            ImageFrames::still(img)
+                        }
+                        None => {
+                            return Err("Could not copy webp data".to_string());
+                        }
                     }
                 }
             }
         }
+    } else if input_path.ends_with(".jxl") {
+        let data = read_image_data(path).await?;
+        println!("Read JPEG XL data {} bytes", data.len());
+        tokio::task::spawn_blocking(move || blocking_jxl_open(&data))
+            .await
+            .map_err(|e| format!("{}", e))??
+    } else if input_path.ends_with(".svg") {
+        let data = read_image_data(path).await?;
+        println!("Read SVG data {} bytes", data.len());
+        let image = tokio::task::spawn_blocking(move || blocking_svg_rasterize(&data, size_hint))
+            .await
+            .map_err(|e| format!("{}", e))??;
+        ImageFrames::still(image)
+    } else if input_path.ends_with(".gif") {
+        let data = read_image_data(path).await?;
+        tokio::task::spawn_blocking(move || blocking_gif_open(data, FrameBudget::default()))
+            .await
+            .map_err(|e| format!("{}", e))??
     } else {
         let result = tokio::task::spawn_blocking(|| blocking_image_open(path))
             .await
             .map_err(|e| format!("{}", e))?;
-        result?
+        ImageFrames::still(result?)
     };
 
-    let dimensions = img.dimensions();
+    let dimensions = img.first().dimensions();
     println!(
-        "Parsed image {} with dimensions {}x{}",
-        input_path, dimensions.0, dimensions.1
+        "Parsed image {} with dimensions {}x{} and {} frame(s)",
+        input_path,
+        dimensions.0,
+        dimensions.1,
+        img.frames.len()
     );
     Ok(LimitedImage { image: img, permit })
 }
 
+async fn read_image_data(path: PathBuf) -> Result<Vec<u8>, String> {
+    use tokio::io::AsyncReadExt;
+    let mut f = File::open(path).await.map_err(|e| format!("{}", e))?;
+    let mut data = Vec::new();
+    f.read_to_end(&mut data)
+        .await
+        .map_err(|e| format!("{}", e))?;
+    Ok(data)
+}
+
 fn blocking_image_open(path: PathBuf) -> Result<RgbaImage, String> {
     let image = ImageReader::open(path)
         .map_err(|e| format!("{}", e))?
@@ -179,12 +313,230 @@ fn blocking_image_open(path: PathBuf) -> Result<RgbaImage, String> {
     Ok(image)
 }
 
+fn blocking_jxl_open(data: &[u8]) -> Result<ImageFrames, String> {
+    use jpegxl_rs::decode::{Endianness, PixelFormat};
+    let mut decoder = jpegxl_rs::decoder_builder()
+        .pixel_format(PixelFormat {
+            num_channels: 4,
+            endianness: Endianness::Native,
+            align: 0,
+        })
+        .build()
+        .map_err(|e| format!("{}", e))?;
+    let (metadata, pixels) = decoder
+        .decode_with::<u8>(data)
+        .map_err(|e| format!("{}", e))?;
+    let image = RgbaImage::from_raw(metadata.width, metadata.height, pixels)
+        .ok_or_else(|| "Could not copy JPEG XL data".to_string())?;
+    Ok(ImageFrames::still(image))
+}
+
+// Every frame of an animation is decoded onto the full canvas
+pub const MAX_ANIMATION_FRAMES: usize = 1000;
+// About 1 GiB of RGBA across all frames
+pub const MAX_ANIMATION_PIXELS: u64 = 1 << 28;
+
+// Limits what decoding an animation may hold in memory
+struct FrameBudget {
+    max_frames: usize,
+    max_pixels: u64,
+    frames: usize,
+    pixels: u64,
+}
+
+impl FrameBudget {
+    fn new(max_frames: usize, max_pixels: u64) -> Self {
+        Self {
+            max_frames,
+            max_pixels,
+            frames: 0,
+            pixels: 0,
+        }
+    }
+
+    // Counts a frame before it is kept
+    fn take(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.frames += 1;
+        self.pixels += width as u64 * height as u64;
+        if self.frames > self.max_frames {
+            return Err(format!(
+                "Animation has more than {} frames",
+                self.max_frames
+            ));
+        }
+        if self.pixels > self.max_pixels {
+            return Err(format!(
+                "Animation has more than {} pixels across its frames",
+                self.max_pixels
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for FrameBudget {
+    fn default() -> Self {
+        Self::new(MAX_ANIMATION_FRAMES, MAX_ANIMATION_PIXELS)
+    }
+}
+
+fn blocking_gif_open(data: Vec<u8>, mut budget: FrameBudget) -> Result<ImageFrames, String> {
+    let repeat = gif_repeat(&data);
+    let decoder = GifDecoder::new(Cursor::new(data)).map_err(|e| format!("{}", e))?;
+    let (width, height) = decoder.dimensions();
+    // Frames are already composited onto the full canvas by the decoder
+    let mut frames = Vec::new();
+    for frame in decoder.into_frames() {
+        budget.take(width, height)?;
+        let frame = frame.map_err(|e| format!("{}", e))?;
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        frames.push(AnimationFrame {
+            delay: numerator / denominator.max(1),
+            image: frame.into_buffer(),
+        });
+    }
+    if frames.is_empty() {
+        return Err("GIF has no frames".to_string());
+    }
+    Ok(ImageFrames { frames, repeat })
+}
+
+fn blocking_animated_webp_open(
+    data: &[u8],
+    mut budget: FrameBudget,
+) -> Result<ImageFrames, String> {
+    // The decoder produces every frame at once, so they are counted from the file first
+    let (width, height) = webp_canvas_size(data)?;
+    for _ in 0..webp_frame_count(data) {
+        budget.take(width, height)?;
+    }
+    let animation = webp::AnimDecoder::new(data).decode()?;
+    let mut frames = Vec::with_capacity(animation.len());
+    // Decoded timestamps mark when each frame ends
+    let mut previous_timestamp = 0;
+    for frame in animation.into_iter() {
+        let rgba = match frame.get_layout() {
+            webp::PixelLayout::Rgba => frame.get_image().to_vec(),
+            webp::PixelLayout::Rgb => frame
+                .get_image()
+                .chunks_exact(3)
+                .flat_map(|p| [p[0], p[1], p[2], 255])
+                .collect(),
+        };
+        let image = RgbaImage::from_raw(frame.width(), frame.height(), rgba)
+            .ok_or_else(|| "Could not copy webp frame data".to_string())?;
+        let timestamp = frame.get_time_ms();
+        frames.push(AnimationFrame {
+            image,
+            delay: (timestamp - previous_timestamp).max(0) as u32,
+        });
+        previous_timestamp = timestamp;
+    }
+    if frames.is_empty() {
+        return Err("WebP animation has no frames".to_string());
+    }
+    // WebP counts total plays, GIF counts repeats after the first play
+    let repeat = match animation.loop_count {
+        0 => Repeat::Infinite,
+        n => Repeat::Finite((n - 1).min(u16::MAX as u32) as u16),
+    };
+    Ok(ImageFrames { frames, repeat })
+}
+
+// Reads the loop count out of the NETSCAPE2.0 application extension,
+// without it a GIF plays once.
+fn gif_repeat(data: &[u8]) -> Repeat {
+    const NETSCAPE: &[u8] = b"\x21\xff\x0bNETSCAPE2.0\x03\x01";
+    match data
+        .windows(NETSCAPE.len())
+        .position(|window| window == NETSCAPE)
+        .map(|index| index + NETSCAPE.len())
+        .and_then(|index| data.get(index..index + 2))
+    {
+        Some(&[0, 0]) => Repeat::Infinite,
+        Some(&[low, high]) => Repeat::Finite(u16::from_le_bytes([low, high])),
+        _ => Repeat::Finite(0),
+    }
+}
+
+// The canvas is stored less one in 24 bits each after the VP8X flags
+fn webp_canvas_size(data: &[u8]) -> Result<(u32, u32), String> {
+    let read = |at: usize| {
+        data.get(at..at + 3)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], 0]) + 1)
+    };
+    match (read(24), read(27)) {
+        (Some(width), Some(height)) => Ok((width, height)),
+        _ => Err("WebP animation has no canvas size".to_string()),
+    }
+}
+
+// Each frame of an animation is an ANMF chunk
+fn webp_frame_count(data: &[u8]) -> usize {
+    let mut count = 0;
+    let mut at = 12;
+    while let Some(header) = data.get(at..at + 8) {
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if &header[0..4] == b"ANMF" {
+            count += 1;
+        }
+        // Chunks are padded to an even size
+        at = at.saturating_add(8).saturating_add(size + (size & 1));
+    }
+    count
+}
+
+// Animated WebP files use the extended format with the animation flag set
+fn is_animated_webp(data: &[u8]) -> bool {
+    data.len() > 20 && &data[0..4] == b"RIFF" && &data[8..16] == b"WEBPVP8X" && data[20] & 0x02 != 0
+}
+
 pub async fn open_image_dimensions_only(
     input_path: &str,
     sem: &ImageSemaphore,
 ) -> Result<(u32, u32), String> {
     let image = open_image(input_path, sem).await?;
-    Ok(image.image.dimensions())
+    Ok(image.image.first().dimensions())
+}
+
+fn blocking_apply_transformations_to_frames(
+    image: ImageFrames,
+    transformations: TransformationList,
+) -> Result<ImageFrames, String> {
+    let ImageFrames { mut frames, repeat } = image;
+    // Frame selection is applied first, every other transformation
+    // is applied to each remaining frame individually
+    let selected_frame = transformations
+        .list_ref()
+        .iter()
+        .rev()
+        .find_map(|t| match t {
+            Transformation::Frame(index) => Some(*index as usize),
+            _ => None,
+        });
+    if let Some(index) = selected_frame {
+        if index >= frames.len() {
+            return Err(format!(
+                "Frame {} requested but image has {} frame(s)",
+                index,
+                frames.len()
+            ));
+        }
+        let mut frame = frames.swap_remove(index);
+        frame.delay = 0;
+        frames = vec![frame];
+    }
+    let mut transformed = Vec::with_capacity(frames.len());
+    for frame in frames {
+        transformed.push(AnimationFrame {
+            image: blocking_apply_transformations(frame.image, transformations.clone())?,
+            delay: frame.delay,
+        });
+    }
+    Ok(ImageFrames {
+        frames: transformed,
+        repeat,
+    })
 }
 
 fn blocking_apply_transformations(
@@ -205,33 +557,38 @@ fn blocking_apply_transformations(
                 resize(&image, w, h, FilterType::Lanczos3)
             }
             Blur(sigma) => blur(&image, *sigma),
-            Background(color) => {
-                let dimensions = image.dimensions();
-                let r: u8 = ((*color & 0xff0000) >> 16) as u8;
-                let g: u8 = ((*color & 0xff00) >> 8) as u8;
-                let b: u8 = (*color & 0xff) as u8;
-                let pixel = Rgba([r, g, b, 255]);
-
-                let mut background = ImageBuffer::from_pixel(dimensions.0, dimensions.1, pixel);
-                overlay(&mut background, &image, 0, 0);
-                background
-            }
+            Background(color) => flatten(&image, *color),
             Crop(x, y, w, h) => crop(&mut image, *x, *y, *w, *h).to_image(),
+            // Frames are selected before per frame transformations
+            Frame(_) => image,
             Noop => image,
         }
     });
     Ok(result)
 }
 
+fn flatten(image: &RgbaImage, color: u32) -> RgbaImage {
+    let dimensions = image.dimensions();
+    let r: u8 = ((color & 0xff0000) >> 16) as u8;
+    let g: u8 = ((color & 0xff00) >> 8) as u8;
+    let b: u8 = (color & 0xff) as u8;
+    let pixel = Rgba([r, g, b, 255]);
+
+    let mut background = ImageBuffer::from_pixel(dimensions.0, dimensions.1, pixel);
+    overlay(&mut background, image, 0, 0);
+    background
+}
+
 pub async fn apply_transformations(
     image: LimitedImage<'_>,
     transformations: TransformationList,
 ) -> Result<LimitedImage<'_>, String> {
     let img = image.image;
-    let result =
-        tokio::task::spawn_blocking(|| blocking_apply_transformations(img, transformations))
-            .await
-            .map_err(|e| format!("{}", e))?;
+    let result = tokio::task::spawn_blocking(|| {
+        blocking_apply_transformations_to_frames(img, transformations)
+    })
+    .await
+    .map_err(|e| format!("{}", e))?;
     Ok(LimitedImage {
         image: result?,
         permit: image.permit,
@@ -250,25 +607,83 @@ fn cursor_to_vec(mut buffer: Cursor<Vec<u8>>) -> Result<Vec<u8>, String> {
 }
 
 fn blocking_encode_in_memory(
-    image: RgbaImage,
+    image: ImageFrames,
     sub: ImageFormat,
     quality: Option<u8>,
+    options: EncoderOptions,
 ) -> Result<Vec<u8>, String> {
+    options.check_format(&sub)?;
+    if image.is_animated() {
+        match sub {
+            ImageFormat::GIF if options.palette.is_some() || options.dither => {
+                return blocking_encode_quantized_gif(image, &options)
+            }
+            ImageFormat::GIF => return blocking_encode_animated_gif(image, &options),
+            ImageFormat::WEBP => return blocking_encode_animated_webp(image, quality, &options),
+            _ => {
+                println!("{:?} does not support animation, using first frame", sub);
+            }
+        }
+    }
+    if sub == ImageFormat::GIF && (options.palette.is_some() || options.dither) {
+        return blocking_encode_quantized_gif(image, &options);
+    }
+    let mut image = image.into_first();
+    // Without a background requested, transparency becomes white rather than
+    // whatever color happens to be under transparent pixels
+    if !sub.has_alpha() && image.pixels().any(|pixel| pixel[3] < 255) {
+        println!("{:?} has no alpha, flattening onto white", sub);
+        image = flatten(&image, 0xffffff);
+    }
     let dimensions = image.dimensions();
     println!(
         "Output image with dimensions {}x{}",
         dimensions.0, dimensions.1
     );
     let format = match sub {
+        ImageFormat::PNG if options.palette.is_some() || options.optimize => {
+            return blocking_encode_png(&image, &options);
+        }
+        ImageFormat::PNG if options.effort.is_some() => {
+            let mut buffer = Cursor::new(Vec::new());
+            let (compression, filter) = png_compression(options.effort.unwrap_or(5));
+            PngEncoder::new_with_quality(&mut buffer, compression, filter)
+                .write_image(
+                    image.as_raw(),
+                    image.width(),
+                    image.height(),
+                    ColorType::Rgba8,
+                )
+                .map_err(|e| format!("{}", e))?;
+            return cursor_to_vec(buffer);
+        }
         ImageFormat::PNG => ImageOutputFormat::Png,
+        ImageFormat::JPEG if options.progressive || options.subsampling.is_some() => {
+            return blocking_encode_jpeg(&image, quality.unwrap_or(75), &options);
+        }
         ImageFormat::JPEG => ImageOutputFormat::Jpeg(quality.unwrap_or(75)),
+        ImageFormat::TIFF => ImageOutputFormat::Tiff,
+        ImageFormat::BMP => ImageOutputFormat::Bmp,
+        ImageFormat::ICO => return blocking_encode_ico(&image),
+        ImageFormat::QOI => {
+            let mut buffer = Cursor::new(Vec::new());
+            QoiEncoder::new(&mut buffer)
+                .write_image(
+                    image.as_raw(),
+                    image.width(),
+                    image.height(),
+                    ColorType::Rgba8,
+                )
+                .map_err(|e| format!("{}", e))?;
+            return cursor_to_vec(buffer);
+        }
         ImageFormat::GIF => {
             let mut buffer = Cursor::new(Vec::new());
 
             // Enclose this in a block so that we do not mutably borrow buffer
             // in more than two places at once
             {
-                let mut encoder = GifEncoder::new_with_speed(&mut buffer, 25);
+                let mut encoder = GifEncoder::new_with_speed(&mut buffer, gif_speed(&options));
                 encoder
                     .encode(
                         image.as_raw(),
@@ -282,8 +697,11 @@ fn blocking_encode_in_memory(
         }
         ImageFormat::AVIF => {
             let mut buffer = Cursor::new(Vec::new());
-            let encoder =
-                AvifEncoder::new_with_speed_quality(&mut buffer, 8, quality.unwrap_or(75));
+            let encoder = AvifEncoder::new_with_speed_quality(
+                &mut buffer,
+                avif_speed(&options),
+                quality.unwrap_or(75),
+            );
             encoder
                 .write_image(
                     image.as_raw(),
@@ -296,9 +714,30 @@ fn blocking_encode_in_memory(
         }
         ImageFormat::WEBP => {
             let encoder = webp::Encoder::from_rgba(image.as_raw(), image.width(), image.height());
-            let encoded = encoder.encode(quality.map(|n| n as f32).unwrap_or(75.0));
+            let config = webp_config(quality, &options)?;
+            let encoded = encoder
+                .encode_advanced(&config)
+                .map_err(|e| format!("{:?}", e))?;
             return Ok(encoded.to_vec());
         }
+        ImageFormat::JXL => {
+            let distance = if options.lossless {
+                0.0
+            } else {
+                jxl_distance(quality.unwrap_or(75))
+            };
+            let mut encoder = jpegxl_rs::encoder_builder()
+                .has_alpha(true)
+                .lossless(distance == 0.0)
+                .quality(distance)
+                .speed(jxl_speed(&options))
+                .build()
+                .map_err(|e| format!("{}", e))?;
+            let encoded: jpegxl_rs::encode::EncoderResult<u8> = encoder
+                .encode::<u8, u8>(image.as_raw(), image.width(), image.height())
+                .map_err(|e| format!("{}", e))?;
+            return Ok(encoded.data);
+        }
         _ => {
             return Err(format!("Unknown type {:?}", sub));
         }
@@ -311,30 +750,582 @@ fn blocking_encode_in_memory(
     cursor_to_vec(buffer)
 }
 
+const ICO_SIZES: [u32; 7] = [16, 24, 32, 48, 64, 128, 256];
+
+// Each size up to the image's own size becomes an entry in the icon,
+// so a large enough source produces a full favicon set.
+fn ico_sizes(width: u32, height: u32) -> Vec<u32> {
+    let largest = width.max(height);
+    let sizes: Vec<u32> = ICO_SIZES
+        .iter()
+        .copied()
+        .filter(|size| *size <= largest)
+        .collect();
+    if sizes.is_empty() {
+        vec![largest.max(1)]
+    } else {
+        sizes
+    }
+}
+
+fn blocking_encode_ico(image: &RgbaImage) -> Result<Vec<u8>, String> {
+    let (width, height) = image.dimensions();
+    let largest = width.max(height).max(1);
+    let mut frames = Vec::new();
+    for size in ico_sizes(width, height) {
+        // Keep the aspect ratio within a size x size square
+        let w = (width * size / largest).max(1);
+        let h = (height * size / largest).max(1);
+        let resized = resize(image, w, h, FilterType::Lanczos3);
+        frames.push(
+            IcoFrame::as_png(resized.as_raw(), w, h, ColorType::Rgba8)
+                .map_err(|e| format!("{}", e))?,
+        );
+    }
+    println!("Output ico with {} sizes", frames.len());
+    let mut buffer = Cursor::new(Vec::new());
+    IcoEncoder::new(&mut buffer)
+        .encode_images(&frames)
+        .map_err(|e| format!("{}", e))?;
+    cursor_to_vec(buffer)
+}
+
+// JPEG XL is tuned by butteraugli distance rather than quality,
+// this follows the same mapping that cjxl uses, 100 is lossless.
+fn jxl_distance(quality: u8) -> f32 {
+    let quality = quality as f32;
+    if quality >= 100.0 {
+        0.0
+    } else if quality >= 30.0 {
+        0.1 + (100.0 - quality) * 0.09
+    } else {
+        53.0 / 3000.0 * quality * quality - 23.0 / 20.0 * quality + 25.0
+    }
+}
+
+fn blocking_encode_jpeg(
+    image: &RgbaImage,
+    quality: u8,
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, String> {
+    use jpeg_encoder::{ColorType as JpegColorType, Encoder, SamplingFactor};
+    let width = u16::try_from(image.width()).map_err(|e| format!("{}", e))?;
+    let height = u16::try_from(image.height()).map_err(|e| format!("{}", e))?;
+    let mut out = Vec::new();
+    let mut encoder = Encoder::new(&mut out, quality);
+    encoder.set_progressive(options.progressive);
+    encoder.set_sampling_factor(match options.subsampling {
+        Some(ChromaSubsampling::Yuv444) => SamplingFactor::R_4_4_4,
+        Some(ChromaSubsampling::Yuv422) => SamplingFactor::R_4_2_2,
+        Some(ChromaSubsampling::Yuv420) | None => SamplingFactor::R_4_2_0,
+    });
+    encoder
+        .encode(image.as_raw(), width, height, JpegColorType::Rgba)
+        .map_err(|e| format!("{}", e))?;
+    println!("Output length is {}", out.len());
+    Ok(out)
+}
+
+fn blocking_encode_png(image: &RgbaImage, options: &EncoderOptions) -> Result<Vec<u8>, String> {
+    let (compression, filter) = png_compression(options.effort.unwrap_or(5));
+    let mut out = Vec::new();
+    match options.palette {
+        Some(colors) => {
+            let quantized = blocking_quantize(
+                image,
+                colors as usize,
+                options.dither,
+                false,
+                quantize_sample(options),
+            );
+            let mut encoder = png::Encoder::new(&mut out, image.width(), image.height());
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_palette(quantized.palette_rgb());
+            encoder.set_trns(quantized.palette_alpha());
+            encoder.set_compression(match compression {
+                CompressionType::Fast => png::Compression::Fast,
+                CompressionType::Best => png::Compression::Best,
+                _ => png::Compression::Default,
+            });
+            let mut writer = encoder.write_header().map_err(|e| format!("{}", e))?;
+            writer
+                .write_image_data(&quantized.indices)
+                .map_err(|e| format!("{}", e))?;
+        }
+        None => {
+            PngEncoder::new_with_quality(&mut out, compression, filter)
+                .write_image(
+                    image.as_raw(),
+                    image.width(),
+                    image.height(),
+                    ColorType::Rgba8,
+                )
+                .map_err(|e| format!("{}", e))?;
+        }
+    }
+    if !options.optimize {
+        println!("Output length is {}", out.len());
+        return Ok(out);
+    }
+    // Searches filters and deflate settings, and reduces the
+    // color type and bit depth when the colors present allow it
+    let mut oxipng_options = oxipng::Options::from_preset(oxipng_preset(options));
+    oxipng_options.strip = oxipng::StripChunks::Safe;
+    let optimized =
+        oxipng::optimize_from_memory(&out, &oxipng_options).map_err(|e| format!("{}", e))?;
+    println!("Optimized png from {} to {}", out.len(), optimized.len());
+    Ok(optimized)
+}
+
+fn blocking_encode_quantized_gif(
+    image: ImageFrames,
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, String> {
+    let (width, height) = image.first().dimensions();
+    let width = u16::try_from(width).map_err(|e| format!("{}", e))?;
+    let height = u16::try_from(height).map_err(|e| format!("{}", e))?;
+    let colors = options.palette.unwrap_or(MAX_PALETTE) as usize;
+    println!(
+        "Output gif with {} frames and up to {} colors",
+        image.frames.len(),
+        colors
+    );
+    let mut out = Vec::new();
+    {
+        let mut encoder =
+            gif::Encoder::new(&mut out, width, height, &[]).map_err(|e| format!("{}", e))?;
+        if image.is_animated() {
+            encoder
+                .set_repeat(match image.repeat {
+                    Repeat::Infinite => gif::Repeat::Infinite,
+                    Repeat::Finite(n) => gif::Repeat::Finite(n),
+                })
+                .map_err(|e| format!("{}", e))?;
+        }
+        for frame in &image.frames {
+            // Every frame gets its own palette
+            let quantized = blocking_quantize(
+                &frame.image,
+                colors,
+                options.dither,
+                true,
+                quantize_sample(options),
+            );
+            let gif_frame = gif::Frame {
+                width,
+                height,
+                // GIF delays are in hundredths of a second
+                delay: (frame.delay / 10).min(u16::MAX as u32) as u16,
+                dispose: gif::DisposalMethod::Background,
+                transparent: quantized.transparent,
+                palette: Some(quantized.palette_rgb()),
+                buffer: std::borrow::Cow::Borrowed(&quantized.indices),
+                ..gif::Frame::default()
+            };
+            encoder
+                .write_frame(&gif_frame)
+                .map_err(|e| format!("{}", e))?;
+        }
+    }
+    println!("Output length is {}", out.len());
+    Ok(out)
+}
+
+// NeuQuant sampling, 1 is slowest and best, 30 is fastest
+fn quantize_sample(options: &EncoderOptions) -> i32 {
+    options
+        .effort
+        .map(|effort| 30 - effort as i32 * 29 / MAX_EFFORT as i32)
+        .unwrap_or(10)
+}
+
+// oxipng presets go from 0 (fastest) to 6 (slowest)
+fn oxipng_preset(options: &EncoderOptions) -> u8 {
+    options
+        .effort
+        .map(|effort| effort * 6 / MAX_EFFORT)
+        .unwrap_or(2)
+}
+
+fn webp_config(quality: Option<u8>, options: &EncoderOptions) -> Result<webp::WebPConfig, String> {
+    let mut config =
+        webp::WebPConfig::new().map_err(|_| "Could not configure webp encoder".to_string())?;
+    config.quality = quality.map(|n| n as f32).unwrap_or(75.0);
+    if options.lossless {
+        config.lossless = 1;
+        // Keep color under fully transparent pixels as well
+        config.exact = 1;
+    }
+    if let Some(effort) = options.effort {
+        // WebP methods go from 0 to 6
+        config.method = (effort as i32 * 6 + 5) / MAX_EFFORT as i32;
+    }
+    Ok(config)
+}
+
+// AVIF speed goes from 1 (slowest) to 10 (fastest)
+fn avif_speed(options: &EncoderOptions) -> u8 {
+    options
+        .effort
+        .map(|effort| (MAX_EFFORT - effort).max(1))
+        .unwrap_or(8)
+}
+
+// GIF speed goes from 1 (slowest) to 30 (fastest)
+fn gif_speed(options: &EncoderOptions) -> i32 {
+    options
+        .effort
+        .map(|effort| 30 - effort as i32 * 29 / MAX_EFFORT as i32)
+        .unwrap_or(25)
+}
+
+fn jxl_speed(options: &EncoderOptions) -> jpegxl_rs::EncoderSpeed {
+    use jpegxl_rs::EncoderSpeed::*;
+    match options.effort {
+        None => Squirrel,
+        Some(0) | Some(1) => Lightning,
+        Some(2) => Thunder,
+        Some(3) => Falcon,
+        Some(4) => Cheetah,
+        Some(5) => Hare,
+        Some(6) => Wombat,
+        Some(7) => Squirrel,
+        Some(8) => Kitten,
+        Some(_) => Tortoise,
+    }
+}
+
+fn png_compression(effort: u8) -> (CompressionType, PngFilterType) {
+    match effort {
+        0..=3 => (CompressionType::Fast, PngFilterType::NoFilter),
+        4..=6 => (CompressionType::Default, PngFilterType::Sub),
+        _ => (CompressionType::Best, PngFilterType::Adaptive),
+    }
+}
+
+fn blocking_encode_animated_gif(
+    image: ImageFrames,
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, String> {
+    println!("Output animated gif with {} frames", image.frames.len());
+    let mut buffer = Cursor::new(Vec::new());
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut buffer, gif_speed(options));
+        encoder
+            .set_repeat(image.repeat)
+            .map_err(|e| format!("{}", e))?;
+        encoder
+            .encode_frames(image.frames.into_iter().map(|frame| {
+                Frame::from_parts(
+                    frame.image,
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(frame.delay, 1),
+                )
+            }))
+            .map_err(|e| format!("{}", e))?;
+    }
+    cursor_to_vec(buffer)
+}
+
+fn blocking_encode_animated_webp(
+    image: ImageFrames,
+    quality: Option<u8>,
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, String> {
+    println!("Output animated webp with {} frames", image.frames.len());
+    let (width, height) = image.first().dimensions();
+    let config = webp_config(quality, options)?;
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(match image.repeat {
+        Repeat::Infinite => 0,
+        Repeat::Finite(n) => n as i32 + 1,
+    });
+    // Encoder timestamps mark when each frame starts
+    let mut timestamp = 0;
+    for frame in &image.frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            frame.image.as_raw(),
+            width,
+            height,
+            timestamp,
+        ));
+        timestamp += frame.delay as i32;
+    }
+    Ok(encoder.encode().to_vec())
+}
+
+fn format_supports_auto_quality(format: &ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::JPEG | ImageFormat::AVIF | ImageFormat::WEBP
+    )
+}
+
+const AUTO_QUALITY_MIN: u8 = 10;
+const AUTO_QUALITY_MAX: u8 = 95;
+
+// Finds the lowest quality that passes, assuming higher qualities pass too
+fn lowest_passing<F>(min: u8, max: u8, mut passes: F) -> Result<Option<u8>, String>
+where
+    F: FnMut(u8) -> Result<bool, String>,
+{
+    let (mut low, mut high) = (min, max);
+    let mut found = None;
+    while low <= high {
+        let middle = low + (high - low) / 2;
+        if passes(middle)? {
+            found = Some(middle);
+            if middle == min {
+                break;
+            }
+            high = middle - 1;
+        } else {
+            low = middle + 1;
+        }
+    }
+    Ok(found)
+}
+
+// Finds the highest quality that passes, assuming lower qualities pass too
+fn highest_passing<F>(min: u8, max: u8, mut passes: F) -> Result<Option<u8>, String>
+where
+    F: FnMut(u8) -> Result<bool, String>,
+{
+    let (mut low, mut high) = (min, max);
+    let mut found = None;
+    while low <= high {
+        let middle = low + (high - low) / 2;
+        if passes(middle)? {
+            found = Some(middle);
+            low = middle + 1;
+        } else {
+            if middle == min {
+                break;
+            }
+            high = middle - 1;
+        }
+    }
+    Ok(found)
+}
+
+fn blocking_decode_for_score(bytes: &[u8], format: &ImageFormat) -> Result<RgbaImage, String> {
+    let image_format = match format {
+        ImageFormat::JPEG => image::ImageFormat::Jpeg,
+        ImageFormat::AVIF => image::ImageFormat::Avif,
+        ImageFormat::WEBP => {
+            return webp::Decoder::new(bytes)
+                .decode()
+                .map(|image| image.to_image().into_rgba8())
+                .ok_or_else(|| "Could not decode webp".to_string());
+        }
+        _ => return Err(format!("{:?} cannot be scored", format)),
+    };
+    Ok(image::load_from_memory_with_format(bytes, image_format)
+        .map_err(|e| format!("{}", e))?
+        .into_rgba8())
+}
+
+// Searches for the quality that meets the score and stays within the byte budget.
+// The budget wins when both cannot be met.
+fn blocking_encode_auto_quality(
+    image: ImageFrames,
+    format: ImageFormat,
+    auto: &AutoQuality,
+    options: EncoderOptions,
+) -> Result<(Vec<u8>, u8), String> {
+    if !format_supports_auto_quality(&format) {
+        return Err(format!("{:?} does not support auto quality", format));
+    }
+    let target_score = auto.target_score();
+    if target_score.is_some() && image.is_animated() {
+        return Err("Animated images can only use a byte budget for auto quality".to_string());
+    }
+    let reference = image.first().clone();
+    let mut encoded: std::collections::HashMap<u8, Vec<u8>> = std::collections::HashMap::new();
+    let mut encode = |quality: u8| -> Result<Vec<u8>, String> {
+        if let Some(bytes) = encoded.get(&quality) {
+            return Ok(bytes.clone());
+        }
+        let bytes = blocking_encode_in_memory(
+            image.clone(),
+            format.clone(),
+            Some(quality),
+            options.clone(),
+        )?;
+        encoded.insert(quality, bytes.clone());
+        Ok(bytes)
+    };
+
+    let mut quality = AUTO_QUALITY_MAX;
+    if let Some(target_score) = target_score {
+        let found = lowest_passing(AUTO_QUALITY_MIN, AUTO_QUALITY_MAX, |quality| {
+            let decoded = blocking_decode_for_score(&encode(quality)?, &format)?;
+            let score = ssim(&reference, &decoded)?;
+            println!("Quality {} has score {}", quality, score);
+            Ok(score >= target_score)
+        })?;
+        quality = found.unwrap_or(AUTO_QUALITY_MAX);
+    }
+    if let Some(max_bytes) = auto.max_bytes {
+        let fits = |length: usize| length <= max_bytes;
+        if !fits(encode(quality)?.len()) {
+            let found = highest_passing(AUTO_QUALITY_MIN, quality, |quality| {
+                let length = encode(quality)?.len();
+                println!("Quality {} has length {}", quality, length);
+                Ok(fits(length))
+            })?;
+            // Nothing fits, the smallest is as close as it gets
+            quality = found.unwrap_or(AUTO_QUALITY_MIN);
+        }
+    }
+    println!("Chose quality {} automatically", quality);
+    Ok((encode(quality)?, quality))
+}
+
 pub struct EncodedImage {
     pub bytes: Vec<u8>,
     pub format: ImageFormat,
     pub width: u32,
     pub height: u32,
+    // The quality used, which may have been chosen automatically
+    pub quality: Option<u8>,
 }
 
 pub async fn encode_in_memory(
     image: LimitedImage<'_>,
     format: ImageFormat,
-    quality: Option<u8>,
+    quality: Option<Quality>,
+    options: EncoderOptions,
 ) -> Result<EncodedImage, String> {
     let img = image.image;
-    let width = img.width();
-    let height = img.height();
-    let encode_format = format.clone();
-    let bytes =
-        tokio::task::spawn_blocking(move || blocking_encode_in_memory(img, encode_format, quality))
-            .await
-            .map_err(|e| format!("{}", e))??;
+    let width = img.first().width();
+    let height = img.first().height();
+    let (bytes, quality, format) = tokio::task::spawn_blocking(move || {
+        let (format, quality) = match format {
+            ImageFormat::AUTO(accepted) => {
+                let format = choose_format(&analyze_image(&img), &accepted, &options);
+                // Lossless formats have no quality to search for
+                let quality = match quality {
+                    Some(Quality::Auto(_)) if !format_supports_auto_quality(&format) => None,
+                    quality => quality,
+                };
+                (format, quality)
+            }
+            format => (format, quality),
+        };
+        match quality {
+            Some(Quality::Auto(auto)) => {
+                blocking_encode_auto_quality(img, format.clone(), &auto, options)
+                    .map(|(bytes, quality)| (bytes, Some(quality), format))
+            }
+            quality => {
+                let quality = quality.and_then(|quality| quality.fixed());
+                blocking_encode_in_memory(img, format.clone(), quality, options)
+                    .map(|bytes| (bytes, quality, format))
+            }
+        }
+    })
+    .await
+    .map_err(|e| format!("{}", e))??;
     Ok(EncodedImage {
         bytes,
         format,
         width,
         height,
+        quality,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gif_repeat_reads_netscape_extension() {
+        let mut data = b"GIF89a".to_vec();
+        data.extend_from_slice(b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x05\x00\x00");
+        assert!(matches!(gif_repeat(&data), Repeat::Finite(5)));
+        let mut data = b"GIF89a".to_vec();
+        data.extend_from_slice(b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00");
+        assert!(matches!(gif_repeat(&data), Repeat::Infinite));
+        assert!(matches!(gif_repeat(b"GIF89a"), Repeat::Finite(0)));
+    }
+
+    fn small_gif(frames: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut data);
+            for _ in 0..frames {
+                let image = RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]));
+                encoder.encode_frame(Frame::new(image)).unwrap();
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn gif_frames_are_limited() {
+        let decoded = blocking_gif_open(small_gif(3), FrameBudget::default()).unwrap();
+        assert_eq!(3, decoded.frames.len());
+        assert!(blocking_gif_open(small_gif(3), FrameBudget::new(2, 1000)).is_err());
+        // 16 pixels a frame
+        assert!(blocking_gif_open(small_gif(3), FrameBudget::new(10, 40)).is_err());
+        assert!(blocking_gif_open(small_gif(3), FrameBudget::new(3, 48)).is_ok());
+    }
+
+    #[test]
+    fn jxl_distance_follows_quality() {
+        assert_eq!(0.0, jxl_distance(100));
+        assert!((jxl_distance(90) - 1.0).abs() < 0.001);
+        assert!((jxl_distance(30) - 6.4).abs() < 0.001);
+        assert!(jxl_distance(10) > jxl_distance(30));
+    }
+
+    #[test]
+    fn effort_maps_to_encoder_speeds() {
+        let fastest = EncoderOptions {
+            effort: Some(0),
+            ..Default::default()
+        };
+        let slowest = EncoderOptions {
+            effort: Some(MAX_EFFORT),
+            ..Default::default()
+        };
+        assert_eq!(10, avif_speed(&fastest));
+        assert_eq!(1, avif_speed(&slowest));
+        assert_eq!(8, avif_speed(&EncoderOptions::default()));
+        assert_eq!(30, gif_speed(&fastest));
+        assert_eq!(1, gif_speed(&slowest));
+    }
+
+    #[test]
+    fn quality_search_finds_boundaries() {
+        assert_eq!(Ok(Some(73)), lowest_passing(10, 95, |q| Ok(q >= 73)));
+        assert_eq!(Ok(Some(10)), lowest_passing(10, 95, |_| Ok(true)));
+        assert_eq!(Ok(None), lowest_passing(10, 95, |_| Ok(false)));
+        assert_eq!(Ok(Some(41)), highest_passing(10, 95, |q| Ok(q <= 41)));
+        assert_eq!(Ok(Some(95)), highest_passing(10, 95, |_| Ok(true)));
+        assert_eq!(Ok(None), highest_passing(10, 95, |_| Ok(false)));
+    }
+
+    #[test]
+    fn ico_sizes_stop_at_image_size() {
+        assert_eq!(vec![16, 24, 32, 48], ico_sizes(48, 40));
+        assert_eq!(ICO_SIZES.to_vec(), ico_sizes(1024, 512));
+        assert_eq!(vec![10], ico_sizes(10, 4));
+    }
+
+    #[test]
+    fn animated_webp_is_detected() {
+        let mut data = b"RIFF\x00\x00\x00\x00WEBPVP8X\x0a\x00\x00\x00".to_vec();
+        data.extend_from_slice(&[0x02, 0, 0, 0]);
+        assert!(is_animated_webp(&data));
+        data[20] = 0x10;
+        assert!(!is_animated_webp(&data));
+        assert!(!is_animated_webp(
+            b"RIFF\x00\x00\x00\x00WEBPVP8 \x00\x00\x00\x00\x00"
+        ));
+    }
+}
@@ -21,46 +21,96 @@ extern crate dotenv;
 pub mod models;
 pub mod schema;
 
+mod auth;
+mod auto_format;
 mod byte_content;
+mod client_hints;
 mod content_encoding;
 mod content_type;
+mod encoder_options;
 mod existing_file_handler;
 mod file_content;
 mod file_things;
 mod find_object;
+mod gc;
 mod image_operations;
+mod lineage;
+mod manifest;
 mod object;
 mod object_blur_hash;
 mod object_image;
+mod palette;
 mod parsing;
+mod quality;
+mod rederive;
 mod server_name;
 mod sqlite;
+mod svg;
 mod transformations;
+mod variant_recipe;
 mod virtual_object;
+mod virtual_object_listing;
+mod virtual_object_move;
+mod virtual_object_revision;
+mod virtual_object_tag;
 
+pub use auth::{check_admin_token, Actor, AdminToken};
+pub use auto_format::AcceptedFormats;
 pub use byte_content::ByteContent;
+pub use client_hints::ClientHints;
 pub use content_encoding::ContentEncodingValue;
 pub use content_type::{content_type_or_from_safe_ext, content_type_to_extension};
+pub use encoder_options::{ChromaSubsampling, EncoderOptions};
 pub use existing_file_handler::ExistingFileHandler;
 pub use file_content::FileContent;
 pub use file_things::*;
 pub use find_object::{
-    find_object_by_parameters, parse_existing_file_request, search_existing_file_query,
+    dominant_object, find_alias_redirect, find_object_by_parameters, find_primary_object,
+    parse_existing_file_request, search_existing_file_query,
 };
+pub use gc::{blocking_collect_garbage, GarbageCollector, GarbageReport, GC_GRACE_SECONDS};
 pub use image_operations::{open_image_dimensions_only, ImageFormat, ImageSemaphore};
+pub use lineage::{
+    build_object_lineage, build_virtual_object_lineage, find_object_ancestors,
+    find_virtual_object_ancestors, find_virtual_object_descendants,
+};
+pub use manifest::build_manifest;
 pub use object::{
-    create_object, find_object_by_file_path, find_object_by_hash, find_object_by_id, update_object,
-    upsert_object, UpsertObjectCommand,
+    create_object, delete_object, delete_objects, file_path_is_referenced,
+    find_derived_descendants, find_object_by_file_path, find_object_by_hash, find_object_by_id,
+    find_object_references, update_object, upsert_object, ObjectReferences, UpsertObjectCommand,
 };
 pub use object_blur_hash::*;
-pub use object_image::derive_transformed_image;
-pub use parsing::{grab_basename, Basename};
+pub use object_image::{derive_transformed_image, derive_variant};
+pub use parsing::{grab_basename, parse_hex_color, Basename};
+pub use quality::{AutoQuality, Quality};
+pub use rederive::rederive_virtual_objects;
 pub use server_name::ServerName;
 pub use sqlite::{connect_pool, Pool};
+pub use svg::sanitize_svg;
 pub use transformations::{Transformation, TransformationList};
+pub use variant_recipe::{
+    apply_variant_recipes, expand_path_template, find_variant_recipes_for_path,
+    parse_variant_recipes, remove_variant_recipes, set_variant_recipes,
+};
 pub use virtual_object::{
-    add_virtual_object_relations, find_or_create_virtual_object_by_object_path,
-    find_related_objects_to_virtual_object, find_virtual_object_by_object_path,
-    replace_virtual_object_relations, set_primary_object, set_primary_object_if_none,
-    update_transformed_virtual_object,
+    add_virtual_object_relations, clear_derived_virtual_object, clear_primary_object,
+    delete_virtual_object, find_derived_virtual_objects,
+    find_or_create_virtual_object_by_object_path, find_related_objects_to_virtual_object,
+    find_virtual_object_by_id, find_virtual_object_by_object_path,
+    replace_virtual_object_relations, resolve_alias, set_default_jpeg_bg, set_primary_object,
+    set_primary_object_if_none, set_virtual_object_alias, update_transformed_virtual_object,
+    AliasResolution, REDIRECT_STATUSES,
+};
+pub use virtual_object_listing::{
+    list_virtual_objects, ListingEntry, DEFAULT_LIST_PAGE, MAX_LIST_PAGE,
+};
+pub use virtual_object_move::{copy_virtual_object, move_virtual_objects, renamed_path};
+pub use virtual_object_revision::{
+    find_revisions, record_revision, revise_virtual_object, revision_retention,
+    rollback_virtual_object, DEFAULT_REVISION_RETENTION,
+};
+pub use virtual_object_tag::{
+    find_tags_for_virtual_object, find_tags_for_virtual_objects, find_virtual_objects_by_tags,
+    parse_tag, parse_tag_list, update_virtual_object_tags, DEFAULT_TAG_PAGE, MAX_TAG_PAGE,
 };
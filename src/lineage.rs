@@ -0,0 +1,288 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use std::collections::{HashMap, HashSet};
+
+use crate::models::{
+    LineageObject, LineageVirtualObject, Object, ObjectLineageResponse, VirtualObject,
+    VirtualObjectLineageResponse,
+};
+use crate::object::{find_derived_descendants, find_object_by_id};
+use crate::virtual_object::{
+    find_related_objects_to_virtual_object, find_virtual_object_by_id, is_content_addressed,
+};
+
+// Steps from the root for each (id, parent) pair, pairs that do not lead to the root are left out
+fn distances(root: i32, nodes: &[(i32, Option<i32>)]) -> HashMap<i32, i32> {
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    for (id, parent) in nodes {
+        if let Some(parent) = parent {
+            children.entry(*parent).or_default().push(*id);
+        }
+    }
+    let mut found = HashMap::new();
+    let mut frontier = vec![root];
+    let mut distance = 0;
+    while !frontier.is_empty() {
+        distance += 1;
+        let mut next = Vec::new();
+        for id in frontier {
+            for child in children.get(&id).into_iter().flatten() {
+                if *child != root && !found.contains_key(child) {
+                    found.insert(*child, distance);
+                    next.push(*child);
+                }
+            }
+        }
+        frontier = next;
+    }
+    found
+}
+
+fn object_paths(conn: &SqliteConnection, ids: &[i32]) -> Result<HashMap<i32, String>, String> {
+    use crate::schema::object;
+    let rows: Vec<(i32, String)> = object::table
+        .filter(object::id.eq_any(ids))
+        .select((object::id, object::file_path))
+        .load(conn)
+        .map_err(|err| format!("{}", err))?;
+    Ok(rows.into_iter().collect())
+}
+
+fn lineage_objects(
+    conn: &SqliteConnection,
+    objects: Vec<(Object, i32)>,
+) -> Result<Vec<LineageObject>, String> {
+    let parents: Vec<i32> = objects
+        .iter()
+        .filter_map(|(object, _)| object.derived_object_id)
+        .collect();
+    let paths = object_paths(conn, &parents)?;
+    Ok(objects
+        .into_iter()
+        .map(|(object, distance)| LineageObject {
+            derived_from: object
+                .derived_object_id
+                .and_then(|id| paths.get(&id).cloned()),
+            path: object.file_path,
+            content_type: object.content_type,
+            content_encoding: object.content_encoding,
+            content_length: object.length,
+            width: object.width,
+            height: object.height,
+            transforms: object.transforms,
+            quality: object.quality,
+            encoder_options: object.encoder_options,
+            created: object.created,
+            distance,
+        })
+        .collect())
+}
+
+// Follows derived_object_id back to the original upload
+pub fn find_object_ancestors(
+    conn: &SqliteConnection,
+    object: &Object,
+) -> Result<Vec<Object>, String> {
+    let mut ancestors: Vec<Object> = Vec::new();
+    let mut seen = HashSet::from([object.id]);
+    let mut parent = object.derived_object_id;
+    while let Some(id) = parent {
+        if !seen.insert(id) {
+            break;
+        }
+        match find_object_by_id(conn, id)? {
+            None => break,
+            Some(found) => {
+                parent = found.derived_object_id;
+                ancestors.push(found);
+            }
+        }
+    }
+    Ok(ancestors)
+}
+
+pub fn build_object_lineage(
+    conn: &SqliteConnection,
+    object: &Object,
+) -> Result<ObjectLineageResponse, String> {
+    let ancestors = find_object_ancestors(conn, object)?
+        .into_iter()
+        .zip(1..)
+        .collect();
+    let descendants = find_derived_descendants(conn, &[object.id])?;
+    let nodes: Vec<(i32, Option<i32>)> = descendants
+        .iter()
+        .map(|o| (o.id, o.derived_object_id))
+        .collect();
+    let depth = distances(object.id, &nodes);
+    let descendants = descendants
+        .into_iter()
+        .map(|o| {
+            let distance = depth.get(&o.id).copied().unwrap_or_default();
+            (o, distance)
+        })
+        .collect();
+    let mut node = lineage_objects(conn, vec![(object.clone(), 0)])?;
+    Ok(ObjectLineageResponse {
+        object: node.remove(0),
+        ancestors: lineage_objects(conn, ancestors)?,
+        descendants: lineage_objects(conn, descendants)?,
+    })
+}
+
+// Follows derived_virtual_object_id back to the virtual object that was uploaded to
+pub fn find_virtual_object_ancestors(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+) -> Result<Vec<VirtualObject>, String> {
+    let mut ancestors: Vec<VirtualObject> = Vec::new();
+    let mut seen = HashSet::from([vobj.id]);
+    let mut parent = vobj.derived_virtual_object_id;
+    while let Some(id) = parent {
+        if !seen.insert(id) {
+            break;
+        }
+        match find_virtual_object_by_id(conn, id)? {
+            None => break,
+            Some(found) => {
+                parent = found.derived_virtual_object_id;
+                ancestors.push(found);
+            }
+        }
+    }
+    Ok(ancestors)
+}
+
+// Virtual objects derived from this one, and those derived from them, and so on
+pub fn find_virtual_object_descendants(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+) -> Result<Vec<VirtualObject>, String> {
+    use crate::schema::virtual_object;
+    let mut descendants: Vec<VirtualObject> = Vec::new();
+    let mut seen = HashSet::from([vobj.id]);
+    let mut frontier = vec![vobj.id];
+    while !frontier.is_empty() {
+        let found: Vec<VirtualObject> = virtual_object::table
+            .filter(virtual_object::derived_virtual_object_id.eq_any(&frontier))
+            .order(virtual_object::id.asc())
+            .load(conn)
+            .map_err(|err| format!("{}", err))?;
+        frontier = Vec::new();
+        for found in found {
+            if seen.insert(found.id) {
+                frontier.push(found.id);
+                descendants.push(found);
+            }
+        }
+    }
+    Ok(descendants)
+}
+
+fn lineage_virtual_objects(
+    conn: &SqliteConnection,
+    vobjs: Vec<(VirtualObject, i32)>,
+    paths: &HashMap<i32, String>,
+) -> Result<Vec<LineageVirtualObject>, String> {
+    // This is an N query, lineages are rarely deep or wide
+    let mut nodes = Vec::with_capacity(vobjs.len());
+    for (vobj, distance) in vobjs {
+        let related = find_related_objects_to_virtual_object(conn, &vobj)?;
+        // The primary object is not always among the related objects
+        let primary_object = match vobj.primary_object_id {
+            None => None,
+            Some(id) => match related.iter().find(|o| o.id == id) {
+                Some(object) => Some(object.clone()),
+                None => find_object_by_id(conn, id)?,
+            },
+        };
+        let placeholder = vobj.derived_virtual_object_id.is_some()
+            && primary_object
+                .as_ref()
+                .map(|o| is_content_addressed(&vobj.object_path, &o.content_hash))
+                .unwrap_or(false);
+        nodes.push(LineageVirtualObject {
+            derived_from: vobj
+                .derived_virtual_object_id
+                .and_then(|id| paths.get(&id).cloned()),
+            primary_object: primary_object.map(|o| o.file_path),
+            placeholder,
+            objects: lineage_objects(conn, related.into_iter().map(|o| (o, 0)).collect())?,
+            path: vobj.object_path,
+            transforms: vobj.transforms,
+            distance,
+        });
+    }
+    Ok(nodes)
+}
+
+pub fn build_virtual_object_lineage(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+) -> Result<VirtualObjectLineageResponse, String> {
+    let ancestors = find_virtual_object_ancestors(conn, vobj)?;
+    let descendants = find_virtual_object_descendants(conn, vobj)?;
+    let nodes: Vec<(i32, Option<i32>)> = descendants
+        .iter()
+        .map(|v| (v.id, v.derived_virtual_object_id))
+        .collect();
+    let depth = distances(vobj.id, &nodes);
+    let mut paths: HashMap<i32, String> = HashMap::new();
+    for v in ancestors.iter().chain(descendants.iter()) {
+        paths.insert(v.id, v.object_path.clone());
+    }
+    paths.insert(vobj.id, vobj.object_path.clone());
+    let ancestors = ancestors.into_iter().zip(1..).collect();
+    let descendants = descendants
+        .into_iter()
+        .map(|v| {
+            let distance = depth.get(&v.id).copied().unwrap_or_default();
+            (v, distance)
+        })
+        .collect();
+    let mut node = lineage_virtual_objects(conn, vec![(vobj.clone(), 0)], &paths)?;
+    Ok(VirtualObjectLineageResponse {
+        virtual_object: node.remove(0),
+        ancestors: lineage_virtual_objects(conn, ancestors, &paths)?,
+        descendants: lineage_virtual_objects(conn, descendants, &paths)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distances_follow_parents_from_the_root() {
+        let nodes = [
+            (2, Some(1)),
+            (3, Some(2)),
+            (4, Some(1)),
+            (5, Some(9)),
+            (1, Some(3)),
+        ];
+        let found = distances(1, &nodes);
+        assert_eq!(found.get(&2), Some(&1));
+        assert_eq!(found.get(&4), Some(&1));
+        assert_eq!(found.get(&3), Some(&2));
+        // Unrelated and cyclic entries are not given a distance
+        assert_eq!(found.get(&5), None);
+        assert_eq!(found.get(&1), None);
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::content_type::{content_type_to_ext, find_known_content_type};
+use crate::models::{
+    DeriveTransformedObjectsResponseBlurHash, ManifestResponse, ManifestResponseFormat,
+    ManifestResponseObject, Object, VirtualObject,
+};
+use crate::object_blur_hash::find_blur_hashes_for_objects;
+use crate::parsing::grab_basename;
+use crate::virtual_object::{find_derived_virtual_objects, find_related_objects_to_virtual_object};
+use diesel::sqlite::SqliteConnection;
+use std::collections::{HashMap, HashSet};
+
+// Formats listed first are offered first in <picture>
+const FORMAT_ORDER: [&str; 7] = [
+    "image/avif",
+    "image/jxl",
+    "image/webp",
+    "image/jpeg",
+    "image/png",
+    "image/gif",
+    "image/svg+xml",
+];
+// Formats that need a <source> because not every browser supports them
+const SOURCE_FORMATS: [&str; 3] = ["image/avif", "image/jxl", "image/webp"];
+// Formats every browser can show in an <img>
+const FALLBACK_FORMATS: [&str; 4] = ["image/jpeg", "image/png", "image/gif", "image/svg+xml"];
+
+// URLs are relative to the host when no public base URL is set
+pub fn public_base_url() -> String {
+    std::env::var("PUBLIC_BASE_URL")
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_default()
+}
+
+pub(crate) fn encode_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' | b'@' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// Builds a URL that the existing file handler resolves to this related object
+pub fn related_object_url(base: &str, vobj_path: &str, object: &Object) -> String {
+    let mut url = format!("{}/", base);
+    if let (Some(width), Some(height)) = (object.width, object.height) {
+        url.push_str(&format!("r{}x{}/", width, height));
+    }
+    url.push_str(&encode_path(vobj_path));
+    let path_type = grab_basename(vobj_path)
+        .find_content_type()
+        .map(|(top, sub)| format!("{}/{}", top, sub));
+    if path_type.as_deref() != Some(object.content_type.as_str()) {
+        if let Some(ext) = find_known_content_type(&object.content_type)
+            .and_then(|(top, sub)| content_type_to_ext(top, sub).ok())
+        {
+            url.push('.');
+            url.push_str(ext);
+        }
+    }
+    url
+}
+
+pub fn srcset(objects: &[ManifestResponseObject]) -> String {
+    let sized: Vec<String> = objects
+        .iter()
+        .filter_map(|o| o.width.map(|width| format!("{} {}w", o.url, width)))
+        .collect();
+    if sized.is_empty() {
+        objects.first().map(|o| o.url.clone()).unwrap_or_default()
+    } else {
+        sized.join(", ")
+    }
+}
+
+pub fn picture_html(
+    formats: &[ManifestResponseFormat],
+    sizes: Option<&str>,
+    alt: &str,
+) -> Option<String> {
+    let fallback = FALLBACK_FORMATS.iter().find_map(|content_type| {
+        formats
+            .iter()
+            .find(|format| format.content_type == *content_type)
+    })?;
+    // The largest fallback has the best chance of looking right everywhere
+    let image = fallback.objects.last()?;
+    let sizes_attribute = sizes
+        .map(|sizes| format!(" sizes=\"{}\"", escape_html(sizes)))
+        .unwrap_or_default();
+    let mut html = String::from("<picture>");
+    for format in formats
+        .iter()
+        .filter(|format| SOURCE_FORMATS.contains(&format.content_type.as_str()))
+    {
+        html.push_str(&format!(
+            "<source type=\"{}\" srcset=\"{}\"{}>",
+            escape_html(&format.content_type),
+            escape_html(&format.srcset),
+            sizes_attribute
+        ));
+    }
+    html.push_str(&format!(
+        "<img src=\"{}\" srcset=\"{}\"{}",
+        escape_html(&image.url),
+        escape_html(&fallback.srcset),
+        sizes_attribute
+    ));
+    if let (Some(width), Some(height)) = (image.width, image.height) {
+        html.push_str(&format!(" width=\"{}\" height=\"{}\"", width, height));
+    }
+    html.push_str(&format!(
+        " alt=\"{}\" loading=\"lazy\" decoding=\"async\"></picture>",
+        escape_html(alt)
+    ));
+    Some(html)
+}
+
+fn format_rank(content_type: &str) -> usize {
+    FORMAT_ORDER
+        .iter()
+        .position(|known| *known == content_type)
+        .unwrap_or(FORMAT_ORDER.len())
+}
+
+pub fn build_manifest(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+    sizes: Option<&str>,
+    alt: &str,
+) -> Result<ManifestResponse, String> {
+    let base = public_base_url();
+    let mut seen = HashSet::new();
+    let mut found: Vec<(String, Object)> = Vec::new();
+    for object in find_related_objects_to_virtual_object(conn, vobj)? {
+        if seen.insert(object.id) {
+            found.push((
+                related_object_url(&base, &vobj.object_path, &object),
+                object,
+            ));
+        }
+    }
+    // Later virtual objects are named paths given to the same derivation,
+    // they are preferred over the hash path made when deriving
+    let mut derived = find_derived_virtual_objects(conn, vobj)?;
+    derived.reverse();
+    for (derived_vobj, object) in derived {
+        if seen.insert(object.id) {
+            let url = format!("{}/{}", base, encode_path(&derived_vobj.object_path));
+            found.push((url, object));
+        }
+    }
+    let ids: Vec<i32> = found.iter().map(|(_, object)| object.id).collect();
+    let mut blur_hashes: HashMap<i32, Vec<DeriveTransformedObjectsResponseBlurHash>> =
+        HashMap::new();
+    for blur_hash in find_blur_hashes_for_objects(conn, &ids)? {
+        blur_hashes.entry(blur_hash.object_id).or_default().push(
+            DeriveTransformedObjectsResponseBlurHash {
+                x: Some(blur_hash.x_components),
+                y: Some(blur_hash.y_components),
+                bg: Some(blur_hash.background).filter(|bg| !bg.is_empty()),
+                hash: blur_hash.hash,
+            },
+        );
+    }
+
+    let mut groups: HashMap<String, Vec<ManifestResponseObject>> = HashMap::new();
+    for (url, object) in found {
+        groups
+            .entry(object.content_type.clone())
+            .or_default()
+            .push(ManifestResponseObject {
+                url,
+                blur_hash: blur_hashes.remove(&object.id).unwrap_or_default(),
+                content_type: object.content_type,
+                content_length: object.length,
+                width: object.width,
+                height: object.height,
+            });
+    }
+    let mut formats: Vec<ManifestResponseFormat> = groups
+        .into_iter()
+        .map(|(content_type, mut objects)| {
+            objects.sort_by_key(|o| (o.width, o.height, o.content_length));
+            ManifestResponseFormat {
+                srcset: srcset(&objects),
+                content_type,
+                objects,
+            }
+        })
+        .collect();
+    formats.sort_by(|a, b| {
+        format_rank(&a.content_type)
+            .cmp(&format_rank(&b.content_type))
+            .then_with(|| a.content_type.cmp(&b.content_type))
+    });
+    let picture = picture_html(&formats, sizes, alt);
+    Ok(ManifestResponse {
+        path: vobj.object_path.clone(),
+        formats,
+        picture,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest_object(url: &str, content_type: &str, width: i32) -> ManifestResponseObject {
+        ManifestResponseObject {
+            url: url.to_string(),
+            content_type: content_type.to_string(),
+            content_length: 100,
+            width: Some(width),
+            height: Some(width / 2),
+            blur_hash: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn related_urls_carry_size_and_type() {
+        assert_eq!(
+            "https://cdn.example/r800x400/photo.jpg",
+            related_object_url(
+                "https://cdn.example",
+                "photo.jpg",
+                &Object::sample(1, "image/jpeg", 800, 400)
+            )
+        );
+        assert_eq!(
+            "/r800x400/my%20photo.webp",
+            related_object_url("", "my photo", &Object::sample(1, "image/webp", 800, 400))
+        );
+    }
+
+    #[test]
+    fn picture_offers_sources_before_fallback() {
+        let webp = vec![
+            manifest_object("/a.webp", "image/webp", 400),
+            manifest_object("/b.webp", "image/webp", 800),
+        ];
+        let jpeg = vec![
+            manifest_object("/a.jpg", "image/jpeg", 400),
+            manifest_object("/b.jpg", "image/jpeg", 800),
+        ];
+        assert_eq!("/a.webp 400w, /b.webp 800w", srcset(&webp));
+        let formats = vec![
+            ManifestResponseFormat {
+                content_type: "image/webp".to_string(),
+                srcset: srcset(&webp),
+                objects: webp,
+            },
+            ManifestResponseFormat {
+                content_type: "image/jpeg".to_string(),
+                srcset: srcset(&jpeg),
+                objects: jpeg,
+            },
+        ];
+        assert_eq!(
+            Some(
+                "<picture><source type=\"image/webp\" srcset=\"/a.webp 400w, /b.webp 800w\" sizes=\"100vw\">\
+                <img src=\"/b.jpg\" srcset=\"/a.jpg 400w, /b.jpg 800w\" sizes=\"100vw\" width=\"800\" height=\"400\" \
+                alt=\"A &quot;cat&quot;\" loading=\"lazy\" decoding=\"async\"></picture>"
+                    .to_string()
+            ),
+            picture_html(&formats, Some("100vw"), "A \"cat\"")
+        );
+        assert_eq!(None, picture_html(&formats[..1], None, ""));
+    }
+}
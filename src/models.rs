@@ -14,7 +14,12 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use super::schema::{object, virtual_object, virtual_object_relation};
+use super::schema::{
+    object, variant_recipe, virtual_object, virtual_object_relation, virtual_object_revision,
+    virtual_object_revision_object, virtual_object_tag,
+};
+use crate::encoder_options::EncoderOptions;
+use crate::quality::Quality;
 use crate::transformations::TransformationList;
 use crate::ContentEncodingValue;
 use rocket::serde::{Deserialize, Serialize};
@@ -36,6 +41,35 @@ pub struct Object {
     pub height: Option<i32>,
     pub content_headers: Option<String>,
     pub quality: Option<i32>,
+    pub encoder_options: Option<String>,
+    // The quality asked for, such as auto_b50000, when it differs from what was encoded
+    pub requested_quality: Option<String>,
+}
+
+// Objects for tests, only the id, type and dimensions differ
+#[cfg(test)]
+impl Object {
+    pub fn sample(id: i32, content_type: &str, width: i32, height: i32) -> Self {
+        Object {
+            id,
+            content_hash: format!("hash{}", id),
+            content_type: content_type.to_string(),
+            content_encoding: "identity".to_string(),
+            length: 100,
+            file_path: format!("hash{}", id),
+            created: 0,
+            modified: 0,
+            derived_object_id: None,
+            transforms: None,
+            transforms_hash: None,
+            width: Some(width),
+            height: Some(height),
+            content_headers: None,
+            quality: None,
+            encoder_options: None,
+            requested_quality: None,
+        }
+    }
 }
 
 #[derive(Insertable)]
@@ -55,6 +89,9 @@ pub struct NewObject {
     pub height: Option<i32>,
     pub content_headers: Option<String>,
     pub quality: Option<i32>,
+    pub encoder_options: Option<String>,
+    // The quality asked for, such as auto_b50000, when it differs from what was encoded
+    pub requested_quality: Option<String>,
 }
 
 #[derive(AsChangeset)]
@@ -71,6 +108,7 @@ pub struct UpdateObject {
     // pub transforms: Option<String>,
     // pub transforms_hash: Option<String>,
     // pub quality: Option<i32>,
+    // pub encoder_options: Option<String>,
 }
 
 #[derive(AsChangeset, Debug)]
@@ -83,7 +121,7 @@ pub struct UpdateTransformedVirtualObject {
     pub transforms_hash: Option<String>,
 }
 
-#[derive(Queryable, Debug)]
+#[derive(Queryable, Debug, Clone)]
 pub struct VirtualObject {
     pub id: i32,
     pub object_path: String,
@@ -92,6 +130,10 @@ pub struct VirtualObject {
     pub primary_object_id: Option<i32>,
     pub transforms: Option<String>,
     pub transforms_hash: Option<String>,
+    // Requests for this path are served from, or redirected to, another
+    pub alias_virtual_object_id: Option<i32>,
+    // Served transparently when absent
+    pub redirect_status: Option<i32>,
 }
 
 #[derive(Insertable)]
@@ -103,6 +145,17 @@ pub struct NewVirtualObject {
     pub primary_object_id: Option<i32>,
     pub transforms: Option<String>,
     pub transforms_hash: Option<String>,
+    pub alias_virtual_object_id: Option<i32>,
+    pub redirect_status: Option<i32>,
+}
+
+#[derive(Queryable, Debug)]
+pub struct ObjectBlurHash {
+    pub object_id: i32,
+    pub x_components: i32,
+    pub y_components: i32,
+    pub background: String,
+    pub hash: String,
 }
 
 #[derive(Insertable)]
@@ -112,9 +165,63 @@ pub struct ReplaceVirtualObjectRelation {
     pub object_id: i32,
 }
 
+#[derive(Insertable)]
+#[table_name = "virtual_object_tag"]
+pub struct NewVirtualObjectTag {
+    pub virtual_object_id: i32,
+    pub tag: String,
+}
+
+#[derive(Queryable, Debug, Clone)]
+pub struct VirtualObjectRevision {
+    pub id: i32,
+    pub virtual_object_id: i32,
+    pub created: i64,
+    pub actor: Option<String>,
+    pub action: String,
+    pub primary_object_id: Option<i32>,
+}
+
+#[derive(Insertable)]
+#[table_name = "virtual_object_revision"]
+pub struct NewVirtualObjectRevision {
+    pub virtual_object_id: i32,
+    pub created: i64,
+    pub actor: Option<String>,
+    pub action: String,
+    pub primary_object_id: Option<i32>,
+}
+
+#[derive(Insertable)]
+#[table_name = "virtual_object_revision_object"]
+pub struct NewVirtualObjectRevisionObject {
+    pub revision_id: i32,
+    pub object_id: i32,
+}
+
+#[derive(Queryable, Debug, Clone)]
+pub struct VariantRecipe {
+    pub id: i32,
+    // Exact virtual object path, or the start of paths when prefix is set
+    pub path: String,
+    pub prefix: bool,
+    // JSON list of DeriveTransformedObjectsRequestObject, paths are templates
+    pub recipes: String,
+    pub modified: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "variant_recipe"]
+pub struct NewVariantRecipe {
+    pub path: String,
+    pub prefix: bool,
+    pub recipes: String,
+    pub modified: i64,
+}
+
 // JSON stuff
 
-#[derive(Serialize)]
+#[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct VirtualObjectInfoResponseObject {
     pub path: String,
@@ -126,8 +233,15 @@ pub struct VirtualObjectInfoResponseObject {
 }
 
 #[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct VirtualObjectInfoResponse {
     pub path: String,
+    pub default_jpeg_bg: Option<String>,
+    // Path of the primary object, or the dominant object when none is set
+    pub primary_object: Option<String>,
+    pub tags: Vec<String>,
+    pub alias_of: Option<String>,
+    pub redirect: Option<i32>,
     pub objects: Vec<VirtualObjectInfoResponseObject>,
 }
 
@@ -137,8 +251,173 @@ pub struct UpsertVirtualObjectRequestObjectReference {
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct UpsertVirtualObjectRequest {
-    pub objects: Vec<UpsertVirtualObjectRequestObjectReference>,
+    // Relations are left alone when absent
+    pub objects: Option<Vec<UpsertVirtualObjectRequestObjectReference>>,
+    // An empty string clears the default background
+    pub default_jpeg_bg: Option<String>,
+    // Path of a related object, an empty string falls back to the dominant object
+    pub primary_object: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateVirtualObjectAliasRequest {
+    // Path of another virtual object, an empty string removes the alias
+    pub alias_of: Option<String>,
+    // 301, 302, 307 or 308 to redirect to the alias instead of serving it,
+    // 0 serves the alias transparently
+    pub redirect: Option<i32>,
+    // A path with objects of its own is only made an alias when this is set
+    #[serde(default)]
+    pub hide_objects: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateVirtualObjectTagsRequest {
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualObjectTagsResponse {
+    pub path: String,
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TaggedVirtualObjectsResponse {
+    pub objects: Vec<VirtualObjectTagsResponse>,
+    // Passed as `after` to get the next page
+    pub next: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListedVirtualObject {
+    pub path: String,
+    pub primary_object: Option<VirtualObjectInfoResponseObject>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListVirtualObjectsResponse {
+    pub objects: Vec<ListedVirtualObject>,
+    // Groups of paths when listing with a delimiter
+    pub prefixes: Vec<String>,
+    // Passed as `after` to get the next page
+    pub next: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteObjectResponse {
+    pub deleted_objects: Vec<String>,
+    pub removed_files: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualObjectRevisionResponse {
+    pub id: i32,
+    pub created: i64,
+    pub actor: Option<String>,
+    pub action: String,
+    pub primary_object: Option<String>,
+    pub objects: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualObjectRevisionsResponse {
+    pub path: String,
+    // Newest first
+    pub revisions: Vec<VirtualObjectRevisionResponse>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LineageObject {
+    pub path: String,
+    pub content_type: String,
+    pub content_encoding: String,
+    pub content_length: i64,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub transforms: Option<String>,
+    pub quality: Option<i32>,
+    pub encoder_options: Option<String>,
+    pub created: i64,
+    // Path of the object this was derived from
+    pub derived_from: Option<String>,
+    // Derivation steps away from the object asked about
+    pub distance: i32,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ObjectLineageResponse {
+    pub object: LineageObject,
+    // Nearest first, ending with the original upload
+    pub ancestors: Vec<LineageObject>,
+    pub descendants: Vec<LineageObject>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LineageVirtualObject {
+    pub path: String,
+    pub transforms: Option<String>,
+    // Path of the virtual object this was derived from
+    pub derived_from: Option<String>,
+    pub primary_object: Option<String>,
+    // Named after the content hash of what was derived, not derived itself
+    pub placeholder: bool,
+    pub objects: Vec<LineageObject>,
+    pub distance: i32,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualObjectLineageResponse {
+    pub virtual_object: LineageVirtualObject,
+    pub ancestors: Vec<LineageVirtualObject>,
+    pub descendants: Vec<LineageVirtualObject>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveVirtualObjectRequest {
+    pub to: String,
+    // Moves every path starting with the given path
+    pub prefix: Option<bool>,
+    // 301, 302, 307 or 308 to leave redirects at the old paths
+    pub redirect: Option<i32>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MovedVirtualObject {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveVirtualObjectResponse {
+    pub moved: Vec<MovedVirtualObject>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyVirtualObjectRequest {
+    pub to: String,
 }
 
 #[derive(Serialize, Debug)]
@@ -150,6 +429,21 @@ pub struct UpsertObjectResponse {
     pub content_length: i64,
     pub width: Option<i32>,
     pub height: Option<i32>,
+    // Paths derived from the previous content that were derived again
+    pub rederived: Vec<String>,
+    // Paths that could not be derived again and still hold the previous derivation
+    pub rederive_failed: Vec<DerivationFailure>,
+    // Variants made by recipes matching the path
+    pub derived: Vec<DeriveTransformedObjectsResponseObject>,
+    // Variants recipes asked for that could not be made
+    pub derive_failed: Vec<DerivationFailure>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DerivationFailure {
+    pub path: String,
+    pub error: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -166,7 +460,11 @@ pub struct DeriveTransformedObjectsRequestObject {
     pub path: String,
     pub transforms: Option<TransformationList>,
     pub content_type: String,
-    pub quality: Option<u8>,
+    pub quality: Option<Quality>,
+    // Background for formats without alpha, overrides default_jpeg_bg
+    pub bg: Option<String>,
+    #[serde(default)]
+    pub encoder_options: EncoderOptions,
     #[serde(default)]
     pub blur_hash: Vec<DeriveTransformedObjectsRequestBlurHash>,
 }
@@ -195,6 +493,50 @@ pub struct DeriveTransformedObjectsResponseObject {
     pub blur_hash: Vec<DeriveTransformedObjectsResponseBlurHash>,
 }
 
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VariantRecipeResponse {
+    pub path: String,
+    pub prefix: bool,
+    pub recipes: rocket::serde::json::Value,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VariantRecipesResponse {
+    // Most specific first, earlier recipes win when paths collide
+    pub recipes: Vec<VariantRecipeResponse>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestResponseObject {
+    pub url: String,
+    pub content_type: String,
+    pub content_length: i64,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub blur_hash: Vec<DeriveTransformedObjectsResponseBlurHash>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestResponseFormat {
+    pub content_type: String,
+    pub srcset: String,
+    pub objects: Vec<ManifestResponseObject>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestResponse {
+    pub path: String,
+    // Ordered from most to least preferred
+    pub formats: Vec<ManifestResponseFormat>,
+    // Absent when there is no image a browser can fall back to
+    pub picture: Option<String>,
+}
+
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct DeriveTransformedObjectsResponse {
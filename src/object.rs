@@ -108,6 +108,143 @@ pub fn find_object_by_file_path(
     Ok(result)
 }
 
+#[derive(Debug, Default, PartialEq)]
+pub struct ObjectReferences {
+    pub relations: i64,
+    pub primaries: i64,
+    pub derived: i64,
+}
+
+impl ObjectReferences {
+    pub fn is_empty(&self) -> bool {
+        *self == ObjectReferences::default()
+    }
+}
+
+pub fn find_object_references(
+    conn: &SqliteConnection,
+    object_id: i32,
+) -> Result<ObjectReferences, String> {
+    use crate::schema::{object, virtual_object, virtual_object_relation};
+    let relations = virtual_object_relation::table
+        .count()
+        .filter(virtual_object_relation::object_id.eq(object_id))
+        .get_result(conn)
+        .map_err(|err| format!("{}", err))?;
+    let primaries = virtual_object::table
+        .count()
+        .filter(virtual_object::primary_object_id.eq(object_id))
+        .get_result(conn)
+        .map_err(|err| format!("{}", err))?;
+    let derived = object::table
+        .count()
+        .filter(object::derived_object_id.eq(object_id))
+        .get_result(conn)
+        .map_err(|err| format!("{}", err))?;
+    Ok(ObjectReferences {
+        relations,
+        primaries,
+        derived,
+    })
+}
+
+// Objects derived from these, and objects derived from those, and so on
+pub fn find_derived_descendants(
+    conn: &SqliteConnection,
+    ids: &[i32],
+) -> Result<Vec<Object>, String> {
+    use crate::schema::object;
+    let mut descendants: Vec<Object> = Vec::new();
+    let mut frontier: Vec<i32> = ids.to_vec();
+    while !frontier.is_empty() {
+        let found: Vec<Object> = object::table
+            .filter(object::derived_object_id.eq_any(&frontier))
+            .load(conn)
+            .map_err(|err| format!("{}", err))?;
+        frontier = found
+            .iter()
+            .map(|o| o.id)
+            .filter(|id| !ids.contains(id) && !descendants.iter().any(|d| d.id == *id))
+            .collect();
+        descendants.extend(found.into_iter().filter(|o| frontier.contains(&o.id)));
+    }
+    Ok(descendants)
+}
+
+pub fn delete_objects(conn: &SqliteConnection, ids: &[i32]) -> Result<(), String> {
+    conn.transaction(|| delete_object_rows(conn, ids))
+        .map_err(|err| format!("{}", err))?;
+    println!("Deleted objects {:?}", ids);
+    Ok(())
+}
+
+// Deletes these objects with their relations, revision entries and blur hashes,
+// virtual objects and revisions that had them as primary keep no primary
+pub(crate) fn delete_object_rows(conn: &SqliteConnection, ids: &[i32]) -> QueryResult<()> {
+    use crate::schema::{
+        object, object_blur_hash, virtual_object, virtual_object_relation, virtual_object_revision,
+        virtual_object_revision_object,
+    };
+    diesel::delete(virtual_object_relation::table)
+        .filter(virtual_object_relation::object_id.eq_any(ids))
+        .execute(conn)?;
+    // History cannot restore what no longer exists
+    diesel::delete(virtual_object_revision_object::table)
+        .filter(virtual_object_revision_object::object_id.eq_any(ids))
+        .execute(conn)?;
+    diesel::update(virtual_object_revision::table)
+        .set(virtual_object_revision::primary_object_id.eq(None::<i32>))
+        .filter(virtual_object_revision::primary_object_id.eq_any(ids))
+        .execute(conn)?;
+    // Virtual objects fall back to their dominant object
+    diesel::update(virtual_object::table)
+        .set(virtual_object::primary_object_id.eq(None::<i32>))
+        .filter(virtual_object::primary_object_id.eq_any(ids))
+        .execute(conn)?;
+    diesel::delete(object_blur_hash::table)
+        .filter(object_blur_hash::object_id.eq_any(ids))
+        .execute(conn)?;
+    diesel::delete(object::table)
+        .filter(object::id.eq_any(ids))
+        .execute(conn)?;
+    Ok(())
+}
+
+// Without cascade an object that anything refers to is kept.
+// Returns every object deleted so that their files can be removed.
+pub fn delete_object(
+    conn: &SqliteConnection,
+    object: &Object,
+    cascade: bool,
+) -> Result<Vec<Object>, String> {
+    if !cascade {
+        let references = find_object_references(conn, object.id)?;
+        if !references.is_empty() {
+            return Err(format!(
+                "Object {} is still referenced by {} virtual objects, is primary for {} and has {} derived objects",
+                object.file_path, references.relations, references.primaries, references.derived
+            ));
+        }
+    }
+    let mut deleted = vec![object.clone()];
+    if cascade {
+        deleted.extend(find_derived_descendants(conn, &[object.id])?);
+    }
+    let ids: Vec<i32> = deleted.iter().map(|o| o.id).collect();
+    delete_objects(conn, &ids)?;
+    Ok(deleted)
+}
+
+pub fn file_path_is_referenced(conn: &SqliteConnection, path: &str) -> Result<bool, String> {
+    use crate::schema::object::dsl::*;
+    let count: i64 = object
+        .count()
+        .filter(file_path.eq(path))
+        .get_result(conn)
+        .map_err(|err| format!("{}", err))?;
+    Ok(count > 0)
+}
+
 pub struct UpsertObjectCommand<'a> {
     pub width: Option<i32>,
     pub height: Option<i32>,
@@ -160,6 +297,8 @@ pub fn upsert_object(
                 // TODO headers
                 content_headers: None,
                 quality: None,
+                encoder_options: None,
+                requested_quality: None,
             };
             create_object(conn, &new_object)?;
         }
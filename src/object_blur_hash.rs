@@ -1,6 +1,6 @@
 use crate::content_type::*;
 use crate::image_operations::*;
-use crate::models::Object;
+use crate::models::{Object, ObjectBlurHash};
 use crate::transformations::*;
 use crate::{ContentEncodingValue, ImageSemaphore, Pool};
 use diesel::prelude::*;
@@ -26,6 +26,18 @@ pub fn find_blur_hash(
     Ok(result)
 }
 
+pub fn find_blur_hashes_for_objects(
+    conn: &SqliteConnection,
+    ids: &[i32],
+) -> Result<Vec<ObjectBlurHash>, String> {
+    use crate::schema::object_blur_hash;
+    let result = object_blur_hash::table
+        .filter(object_blur_hash::object_id.eq_any(ids))
+        .load(conn)
+        .map_err(|err| format!("{}", err))?;
+    Ok(result)
+}
+
 pub fn save_blur_hash(
     conn: &SqliteConnection,
     id: i32,
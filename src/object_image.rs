@@ -1,32 +1,69 @@
 use crate::content_encoding::*;
 use crate::content_type::*;
+use crate::encoder_options::EncoderOptions;
 use crate::file_things::*;
 use crate::image_operations::*;
+use crate::lineage::find_virtual_object_ancestors;
 use crate::models::*;
 use crate::object::*;
+use crate::object_blur_hash::create_blur_hash;
+use crate::parsing::parse_hex_color;
+use crate::quality::Quality;
 use crate::sqlite::*;
 use crate::transformations::*;
 use crate::virtual_object::*;
+use crate::virtual_object_revision::revise_virtual_object;
 use std::time::SystemTime;
 
 pub async fn read_transform_encode(
     file_path: &str,
     transformations: TransformationList,
-    quality: Option<u8>,
+    quality: Option<Quality>,
+    options: EncoderOptions,
     format: ImageFormat,
     sem: &ImageSemaphore,
 ) -> Result<EncodedImage, String> {
-    let opened_image = open_image(file_path, sem).await?;
+    // Vector images are rasterized directly to the first requested size
+    let size_hint = match transformations.list_ref().first() {
+        Some(Transformation::Resize(w, h)) => Some((*w, *h)),
+        _ => None,
+    };
+    let opened_image = open_image_sized(file_path, size_hint, sem).await?;
     let transformed_image = apply_transformations(opened_image, transformations).await?;
-    encode_in_memory(transformed_image, format, quality).await
+    encode_in_memory(transformed_image, format, quality, options).await
+}
+
+// Formats without alpha are flattened onto the requested background,
+// or the virtual object's default background when there is one
+pub fn flatten_for_format(
+    transformations: TransformationList,
+    format: &ImageFormat,
+    background: Option<u32>,
+    vobj: Option<&VirtualObject>,
+) -> Result<TransformationList, String> {
+    if format.has_alpha() {
+        return Ok(transformations);
+    }
+    let background = match (background, vobj.and_then(|v| v.default_jpeg_bg.as_deref())) {
+        (Some(color), _) => Some(color),
+        (None, Some(default_jpeg_bg)) => Some(parse_hex_color(default_jpeg_bg)?),
+        (None, None) => None,
+    };
+    Ok(match background {
+        Some(color) => transformations.with_background(color),
+        None => transformations,
+    })
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn derive_transformed_image(
     object: &Object,
     vobj: Option<&VirtualObject>,
     transformations: TransformationList,
-    quality: Option<u8>,
+    quality: Option<Quality>,
+    options: EncoderOptions,
     format: Option<ImageFormat>,
+    background: Option<u32>,
     sem: &ImageSemaphore,
     pool: &Pool,
 ) -> Result<(Object, VirtualObject), String> {
@@ -63,26 +100,48 @@ pub async fn derive_transformed_image(
         Ok(supported_format) => supported_format,
     };
 
-    // By default use the same format as the input
-    let encoded_format = format.unwrap_or(input_format);
-    let (content_type, fs_ext) = {
-        let (top, sub) = encoded_format.content_type()?;
-        let content_type = format!("{}/{}", top, sub);
-        let fs_ext = content_type_to_ext(top, sub)?;
-        (content_type, fs_ext)
+    // By default use the same format as the input,
+    // vector images can only be output as raster images
+    let encoded_format = match (format, input_format) {
+        (Some(format), _) => format,
+        (None, ImageFormat::SVG) => ImageFormat::PNG,
+        (None, input_format) => input_format,
     };
-
+    if encoded_format == ImageFormat::SVG {
+        return Err("Images cannot be encoded as SVG".to_string());
+    }
+    let transformations = flatten_for_format(transformations, &encoded_format, background, vobj)?;
     let transformation_string = transformations.to_string();
-    let transformations_hash = hash_bytes_b64(transformation_string.as_bytes())?;
-    let content_quality = quality;
+    let encoder_options = options.to_database();
+    // Encoder options are part of what makes a derived object distinct,
+    // without any the hash stays the same as before they existed
+    let transformations_hash = match &encoder_options {
+        Some(encoder_options) => {
+            hash_bytes_b64(format!("{};{}", transformation_string, encoder_options).as_bytes())?
+        }
+        None => hash_bytes_b64(transformation_string.as_bytes())?,
+    };
+    // Automatic quality is kept so deriving again searches again
+    let requested_quality = quality
+        .as_ref()
+        .filter(|quality| quality.fixed().is_none())
+        .map(|quality| quality.to_string());
     let encoded_image = read_transform_encode(
         &object.file_path,
         transformations,
         quality,
+        options,
         encoded_format,
         sem,
     )
     .await?;
+    // Automatic formats are only known once encoded
+    let (content_type, fs_ext) = {
+        let (top, sub) = encoded_image.format.content_type()?;
+        let content_type = format!("{}/{}", top, sub);
+        let fs_ext = content_type_to_ext(top, sub)?;
+        (content_type, fs_ext)
+    };
     let content_hash = keyed_hash_bytes_b64(&encoded_image.bytes)?;
     let length = encoded_image.bytes.len() as i64;
     let created = SystemTime::now()
@@ -112,7 +171,9 @@ pub async fn derive_transformed_image(
         width: Some(encoded_image.width as i32),
         height: Some(encoded_image.height as i32),
         content_headers: None,
-        quality: content_quality.map(|q| q as i32),
+        quality: encoded_image.quality.map(|q| q as i32),
+        encoder_options,
+        requested_quality,
     };
     let conn = pool.get().map_err(|e| format!("{}", e))?;
     let (default_jpeg_bg, derived_virtual_object_id) = match vobj {
@@ -153,3 +214,86 @@ pub async fn derive_transformed_image(
 
     Ok((object, virtual_object))
 }
+
+// Derives a requested variant of the object and points the requested path at it
+#[allow(clippy::too_many_arguments)]
+pub async fn derive_variant(
+    object: &Object,
+    vobj: &VirtualObject,
+    request: &DeriveTransformedObjectsRequestObject,
+    path: &str,
+    actor: Option<&str>,
+    sem: &ImageSemaphore,
+    pool: &Pool,
+) -> Result<DeriveTransformedObjectsResponseObject, String> {
+    // Deriving into the source or what it came from would derive it from itself
+    {
+        let conn = pool.get().map_err(|e| format!("{}", e))?;
+        let ancestors = find_virtual_object_ancestors(&conn, vobj)?;
+        if std::iter::once(vobj)
+            .chain(ancestors.iter())
+            .any(|v| v.object_path == path)
+        {
+            return Err(format!(
+                "Cannot derive {} from {}, it is derived from it",
+                path, vobj.object_path
+            ));
+        }
+    }
+    let transforms = request
+        .transforms
+        .clone()
+        .unwrap_or_else(TransformationList::empty);
+    let background = request.bg.as_deref().map(parse_hex_color).transpose()?;
+    let (object, virtual_object) = derive_transformed_image(
+        object,
+        Some(vobj),
+        transforms,
+        request.quality.clone(),
+        request.encoder_options.clone(),
+        request.content_type.parse::<ImageFormat>().ok(),
+        background,
+        sem,
+        pool,
+    )
+    .await?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    let derived_vobj = find_or_create_virtual_object_by_object_path(&conn, path)?;
+    let update = UpdateTransformedVirtualObject {
+        default_jpeg_bg: virtual_object.default_jpeg_bg,
+        derived_virtual_object_id: virtual_object.derived_virtual_object_id,
+        primary_object_id: Some(object.id),
+        transforms: object.transforms.clone(),
+        transforms_hash: object.transforms_hash.clone(),
+    };
+
+    revise_virtual_object(&conn, &derived_vobj, actor, "derive", || {
+        replace_virtual_object_relations(&conn, std::slice::from_ref(&object), &derived_vobj)?;
+        println!("Replaced object relations {:?}", object);
+        println!("Updating virtual object {:?}", update);
+        update_transformed_virtual_object(&conn, derived_vobj.id, update)
+    })?;
+    let mut blur_hashes = Vec::with_capacity(request.blur_hash.len());
+    for blur_hash in &request.blur_hash {
+        let bg = blur_hash.bg.clone();
+        let hash = create_blur_hash(
+            &object,
+            blur_hash.x.unwrap_or(3),
+            blur_hash.y.unwrap_or(3),
+            blur_hash.bg.clone(),
+            sem,
+            pool,
+        )
+        .await?;
+        blur_hashes.push(DeriveTransformedObjectsResponseBlurHash {
+            x: blur_hash.x,
+            y: blur_hash.y,
+            bg,
+            hash,
+        });
+    }
+    Ok(DeriveTransformedObjectsResponseObject {
+        path: path.to_string(),
+        blur_hash: blur_hashes,
+    })
+}
@@ -0,0 +1,172 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use color_quant::NeuQuant;
+use image::RgbaImage;
+
+// Below this alpha, pixels become the transparent index when alpha is binary
+const ALPHA_THRESHOLD: u8 = 128;
+
+pub struct QuantizedImage {
+    // RGBA entries
+    pub palette: Vec<[u8; 4]>,
+    pub indices: Vec<u8>,
+    // Only set when alpha is binary and some pixels are transparent
+    pub transparent: Option<u8>,
+}
+
+impl QuantizedImage {
+    pub fn palette_rgb(&self) -> Vec<u8> {
+        self.palette
+            .iter()
+            .flat_map(|color| [color[0], color[1], color[2]])
+            .collect()
+    }
+    pub fn palette_alpha(&self) -> Vec<u8> {
+        self.palette.iter().map(|color| color[3]).collect()
+    }
+}
+
+// Reduces the image to at most `colors` colors.
+// GIF only has a single transparent color, so `binary_alpha` reserves
+// one entry for it and treats every other pixel as opaque.
+// `sample` is passed to NeuQuant, 1 is slowest and best, 30 is fastest.
+pub fn blocking_quantize(
+    image: &RgbaImage,
+    colors: usize,
+    dither: bool,
+    binary_alpha: bool,
+    sample: i32,
+) -> QuantizedImage {
+    let colors = colors.clamp(2, 256);
+    let has_transparent = binary_alpha && image.pixels().any(|pixel| pixel[3] < ALPHA_THRESHOLD);
+    let samples: Vec<u8> = if binary_alpha {
+        image
+            .pixels()
+            .filter(|pixel| pixel[3] >= ALPHA_THRESHOLD)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+            .collect()
+    } else {
+        image.as_raw().clone()
+    };
+    let palette_size = if has_transparent { colors - 1 } else { colors };
+    let (quantizer, mut palette) = if samples.is_empty() {
+        (None, Vec::new())
+    } else {
+        let quantizer = NeuQuant::new(sample, palette_size, &samples);
+        let palette: Vec<[u8; 4]> = quantizer
+            .color_map_rgba()
+            .chunks_exact(4)
+            .map(|c| [c[0], c[1], c[2], c[3]])
+            .collect();
+        (Some(quantizer), palette)
+    };
+    let transparent = if has_transparent {
+        palette.push([0, 0, 0, 0]);
+        Some((palette.len() - 1) as u8)
+    } else {
+        None
+    };
+
+    let (width, height) = image.dimensions();
+    let width = width as usize;
+    let mut indices = Vec::with_capacity(width * height as usize);
+    // Floyd-Steinberg error for the current and next row
+    let mut error = vec![[0f32; 4]; width + 2];
+    let mut next_error = vec![[0f32; 4]; width + 2];
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = image.get_pixel(x as u32, y);
+            if let (Some(index), true) = (transparent, pixel[3] < ALPHA_THRESHOLD) {
+                indices.push(index);
+                continue;
+            }
+            let quantizer = match &quantizer {
+                Some(quantizer) => quantizer,
+                None => {
+                    indices.push(0);
+                    continue;
+                }
+            };
+            let mut wanted = [0u8; 4];
+            let mut wanted_f = [0f32; 4];
+            for channel in 0..4 {
+                let value = if binary_alpha && channel == 3 {
+                    255.0
+                } else if dither {
+                    (pixel[channel] as f32 + error[x + 1][channel]).clamp(0.0, 255.0)
+                } else {
+                    pixel[channel] as f32
+                };
+                wanted_f[channel] = value;
+                wanted[channel] = value.round() as u8;
+            }
+            let index = quantizer.index_of(&wanted);
+            indices.push(index as u8);
+            if dither {
+                let chosen = palette[index];
+                for channel in 0..4 {
+                    let diff = wanted_f[channel] - chosen[channel] as f32;
+                    error[x + 2][channel] += diff * 7.0 / 16.0;
+                    next_error[x][channel] += diff * 3.0 / 16.0;
+                    next_error[x + 1][channel] += diff * 5.0 / 16.0;
+                    next_error[x + 2][channel] += diff / 16.0;
+                }
+            }
+        }
+        std::mem::swap(&mut error, &mut next_error);
+        next_error.iter_mut().for_each(|e| *e = [0.0; 4]);
+    }
+    if palette.is_empty() {
+        palette.push([0, 0, 0, 0]);
+    }
+    println!(
+        "Quantized image to {} colors, dithered: {}",
+        palette.len(),
+        dither
+    );
+    QuantizedImage {
+        palette,
+        indices,
+        transparent,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn transparent_pixels_get_their_own_index() {
+        let mut image = RgbaImage::from_pixel(4, 4, Rgba([200, 10, 10, 255]));
+        image.put_pixel(0, 0, Rgba([0, 0, 0, 0]));
+        let quantized = blocking_quantize(&image, 16, true, true, 10);
+        let transparent = quantized.transparent.unwrap();
+        assert_eq!(transparent, quantized.indices[0]);
+        assert!(quantized.indices[1..].iter().all(|i| *i != transparent));
+        assert!(quantized.palette.len() <= 16);
+        assert_eq!(16, quantized.indices.len());
+    }
+
+    #[test]
+    fn opaque_images_have_no_transparent_index() {
+        let image = RgbaImage::from_pixel(2, 2, Rgba([1, 2, 3, 255]));
+        let quantized = blocking_quantize(&image, 256, false, true, 10);
+        assert_eq!(None, quantized.transparent);
+        assert_eq!(4, quantized.indices.len());
+    }
+}
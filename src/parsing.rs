@@ -89,9 +89,81 @@ impl Basename<'_> {
     }
 }
 
+// Largest device pixel ratio that will be honored
+pub const MAX_DPR: f32 = 4.0;
+
+pub fn valid_dpr(dpr: f32) -> Option<f32> {
+    if dpr > 0.0 && dpr <= MAX_DPR {
+        Some(dpr)
+    } else {
+        None
+    }
+}
+
+// Finds a density suffix like photo@2x.jpg, returning the path without it
+pub fn strip_density_suffix(raw_path: &str) -> Option<(String, f32)> {
+    let parsed = grab_basename(raw_path);
+    let at_index = parsed.basename_no_ext.rfind('@')?;
+    let dpr = parsed.basename_no_ext[at_index + 1..]
+        .strip_suffix('x')?
+        .parse::<f32>()
+        .ok()
+        .and_then(valid_dpr)?;
+    let start = parsed.basename_no_ext_range.start + at_index;
+    let end = parsed.basename_no_ext_range.end;
+    Some((format!("{}{}", &raw_path[..start], &raw_path[end..]), dpr))
+}
+
+// Colors are hex like blur hash backgrounds, "deb836", "#deb836" and "fff" are accepted
+pub fn parse_hex_color(input: &str) -> Result<u32, String> {
+    let hex = input.strip_prefix('#').unwrap_or(input);
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Could not decode color '{}'", input));
+    }
+    match hex.len() {
+        3 => {
+            let short = u32::from_str_radix(hex, 16).map_err(|e| format!("{}", e))?;
+            let r = (short >> 8) & 0xf;
+            let g = (short >> 4) & 0xf;
+            let b = short & 0xf;
+            Ok(((r * 0x11) << 16) | ((g * 0x11) << 8) | (b * 0x11))
+        }
+        6 => u32::from_str_radix(hex, 16).map_err(|e| format!("{}", e)),
+        _ => Err(format!("Could not decode color '{}'", input)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[test]
+    fn density_suffix_is_stripped() {
+        assert_eq!(
+            Some(("a/photo.jpg".to_string(), 2.0)),
+            strip_density_suffix("a/photo@2x.jpg")
+        );
+        assert_eq!(
+            Some(("photo.png.gz".to_string(), 1.5)),
+            strip_density_suffix("photo@1.5x.png.gz")
+        );
+        assert_eq!(
+            Some(("photo".to_string(), 3.0)),
+            strip_density_suffix("photo@3x")
+        );
+        assert_eq!(None, strip_density_suffix("me@example.jpg"));
+        assert_eq!(None, strip_density_suffix("photo@10x.jpg"));
+        assert_eq!(None, strip_density_suffix("a@2x/photo.jpg"));
+    }
+
+    #[test]
+    fn hex_colors_parse() {
+        assert_eq!(Ok(0xdeb836), parse_hex_color("deb836"));
+        assert_eq!(Ok(0xdeb836), parse_hex_color("#DEB836"));
+        assert_eq!(Ok(0xffaa00), parse_hex_color("#fa0"));
+        assert!(parse_hex_color("#fa").is_err());
+        assert!(parse_hex_color("+fffff").is_err());
+    }
+
     #[test]
     fn path_strip_with_content_type_and_encoding() {
         assert_eq!(
@@ -0,0 +1,266 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use image::RgbaImage;
+use std::fmt;
+use std::str::FromStr;
+
+// Used when auto quality is requested without a byte budget or score
+pub const DEFAULT_AUTO_SCORE: f64 = 0.98;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct AutoQuality {
+    pub max_bytes: Option<usize>,
+    // Structural similarity against the unencoded image, 1.0 is identical
+    pub min_score: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Quality {
+    Fixed(u8),
+    Auto(AutoQuality),
+}
+
+impl Quality {
+    pub fn fixed(&self) -> Option<u8> {
+        match self {
+            Quality::Fixed(quality) => Some(*quality),
+            Quality::Auto(_) => None,
+        }
+    }
+}
+
+impl AutoQuality {
+    pub fn target_score(&self) -> Option<f64> {
+        match (self.max_bytes, self.min_score) {
+            (None, None) => Some(DEFAULT_AUTO_SCORE),
+            (_, score) => score,
+        }
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Quality::Fixed(quality) => write!(f, "{}", quality),
+            Quality::Auto(auto) => {
+                write!(f, "auto")?;
+                if let Some(max_bytes) = auto.max_bytes {
+                    write!(f, "_b{}", max_bytes)?;
+                }
+                if let Some(min_score) = auto.min_score {
+                    write!(f, "_s{}", min_score)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FromStr for Quality {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(rest) = s.strip_prefix("auto") {
+            let mut auto = AutoQuality {
+                max_bytes: None,
+                min_score: None,
+            };
+            for part in rest.split('_').skip(1) {
+                if let Some(bytes) = part.strip_prefix('b') {
+                    auto.max_bytes = Some(bytes.parse::<usize>().map_err(|e| format!("{}", e))?);
+                } else if let Some(score) = part.strip_prefix('s') {
+                    let score = score.parse::<f64>().map_err(|e| format!("{}", e))?;
+                    if !(0.0..=1.0).contains(&score) {
+                        return Err(format!("Score {} is not between 0 and 1", score));
+                    }
+                    auto.min_score = Some(score);
+                } else {
+                    return Err(format!("Could not parse {} into an auto quality", part));
+                }
+            }
+            if !rest.is_empty() && !rest.starts_with('_') {
+                return Err(format!("Could not parse {} into a quality", s));
+            }
+            return Ok(Quality::Auto(auto));
+        }
+        let quality = s.parse::<u8>().map_err(|e| format!("{}", e))?;
+        if quality > 100 {
+            return Err(format!("Quality {} is over 100", quality));
+        }
+        Ok(Quality::Fixed(quality))
+    }
+}
+
+impl<'r> rocket::form::FromFormField<'r> for Quality {
+    fn from_value(field: rocket::form::ValueField<'r>) -> rocket::form::Result<'r, Self> {
+        field
+            .value
+            .parse::<Quality>()
+            .map_err(|err| rocket::form::Errors::from(rocket::form::Error::validation(err)))
+    }
+}
+
+// Accepts a number for a fixed quality, or a string like "auto_b50000"
+impl<'de> serde::Deserialize<'de> for Quality {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Helper;
+
+        impl<'de> serde::de::Visitor<'de> for Helper {
+            type Value = Quality;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(formatter, "number or string")
+            }
+
+            fn visit_u64<E>(self, value: u64) -> std::result::Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                match u8::try_from(value) {
+                    Ok(quality) if quality <= 100 => Ok(Quality::Fixed(quality)),
+                    _ => Err(serde::de::Error::custom(format!(
+                        "Quality {} is over 100",
+                        value
+                    ))),
+                }
+            }
+
+            fn visit_str<E>(self, value: &str) -> std::result::Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                value.parse::<Quality>().map_err(serde::de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Helper)
+    }
+}
+
+// Transparent areas are compared as if they were on white
+fn luma(image: &RgbaImage) -> Vec<f64> {
+    image
+        .pixels()
+        .map(|p| {
+            let alpha = p[3] as f64 / 255.0;
+            let y = 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64;
+            y * alpha + 255.0 * (1.0 - alpha)
+        })
+        .collect()
+}
+
+const SSIM_WINDOW: usize = 8;
+const SSIM_STRIDE: usize = 4;
+
+// Mean structural similarity of the luma channel over 8x8 windows
+pub fn ssim(reference: &RgbaImage, candidate: &RgbaImage) -> Result<f64, String> {
+    if reference.dimensions() != candidate.dimensions() {
+        return Err(format!(
+            "Cannot compare {:?} to {:?}",
+            reference.dimensions(),
+            candidate.dimensions()
+        ));
+    }
+    let (width, height) = (reference.width() as usize, reference.height() as usize);
+    let a = luma(reference);
+    let b = luma(candidate);
+    let c1 = (0.01 * 255.0f64).powi(2);
+    let c2 = (0.03 * 255.0f64).powi(2);
+    let window_w = SSIM_WINDOW.min(width);
+    let window_h = SSIM_WINDOW.min(height);
+    let mut total = 0.0;
+    let mut windows = 0;
+    let mut y = 0;
+    while y + window_h <= height {
+        let mut x = 0;
+        while x + window_w <= width {
+            let n = (window_w * window_h) as f64;
+            let (mut sum_a, mut sum_b) = (0.0, 0.0);
+            for row in y..y + window_h {
+                for col in x..x + window_w {
+                    sum_a += a[row * width + col];
+                    sum_b += b[row * width + col];
+                }
+            }
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let (mut var_a, mut var_b, mut covariance) = (0.0, 0.0, 0.0);
+            for row in y..y + window_h {
+                for col in x..x + window_w {
+                    let da = a[row * width + col] - mean_a;
+                    let db = b[row * width + col] - mean_b;
+                    var_a += da * da;
+                    var_b += db * db;
+                    covariance += da * db;
+                }
+            }
+            var_a /= n;
+            var_b /= n;
+            covariance /= n;
+            total += ((2.0 * mean_a * mean_b + c1) * (2.0 * covariance + c2))
+                / ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2));
+            windows += 1;
+            x += SSIM_STRIDE;
+        }
+        y += SSIM_STRIDE;
+    }
+    if windows == 0 {
+        return Err("Image is empty".to_string());
+    }
+    Ok(total / windows as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn quality_decodes_as_expected() {
+        assert_eq!(Ok(Quality::Fixed(80)), "80".parse::<Quality>());
+        assert_eq!(
+            Ok(Quality::Auto(AutoQuality {
+                max_bytes: Some(50000),
+                min_score: Some(0.95),
+            })),
+            "auto_b50000_s0.95".parse::<Quality>()
+        );
+        assert!("101".parse::<Quality>().is_err());
+        assert!("automatic".parse::<Quality>().is_err());
+        assert!("auto_s2".parse::<Quality>().is_err());
+    }
+
+    #[test]
+    fn auto_quality_defaults_to_a_score() {
+        let auto = AutoQuality {
+            max_bytes: None,
+            min_score: None,
+        };
+        assert_eq!(Some(DEFAULT_AUTO_SCORE), auto.target_score());
+        assert_eq!("auto", Quality::Auto(auto).to_string());
+    }
+
+    #[test]
+    fn ssim_is_one_for_identical_images() {
+        let mut image = RgbaImage::from_pixel(16, 16, Rgba([10, 200, 30, 255]));
+        image.put_pixel(3, 3, Rgba([255, 255, 255, 255]));
+        assert!((ssim(&image, &image).unwrap() - 1.0).abs() < 1e-9);
+        let other = RgbaImage::from_pixel(16, 16, Rgba([10, 200, 30, 255]));
+        assert!(ssim(&image, &other).unwrap() < 1.0);
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::content_type::find_known_content_type;
+use crate::encoder_options::EncoderOptions;
+use crate::find_object::find_primary_object;
+use crate::image_operations::{ImageFormat, ImageSemaphore};
+use crate::models::{DerivationFailure, Object, UpdateTransformedVirtualObject, VirtualObject};
+use crate::object_blur_hash::{create_blur_hash, find_blur_hashes_for_objects};
+use crate::object_image::derive_transformed_image;
+use crate::quality::Quality;
+use crate::sqlite::Pool;
+use crate::transformations::TransformationList;
+use crate::virtual_object::{
+    find_derived_virtual_objects, is_content_addressed, replace_virtual_object_relations,
+    update_transformed_virtual_object,
+};
+use crate::virtual_object_revision::revise_virtual_object;
+use std::collections::HashSet;
+
+// The format and quality of a previous derivation, so the next one matches
+fn derivation_settings(object: &Object) -> (Option<ImageFormat>, Option<Quality>, EncoderOptions) {
+    let format = find_known_content_type(&object.content_type)
+        .and_then(|(_, sub)| sub.parse::<ImageFormat>().ok());
+    let requested = object
+        .requested_quality
+        .as_deref()
+        .and_then(|quality| quality.parse::<Quality>().ok());
+    let quality = requested.or_else(|| {
+        object
+            .quality
+            .and_then(|quality| u8::try_from(quality).ok())
+            .map(Quality::Fixed)
+    });
+    let options = object
+        .encoder_options
+        .as_deref()
+        .and_then(|options| options.parse::<EncoderOptions>().ok())
+        .unwrap_or_default();
+    (format, quality, options)
+}
+
+async fn rederive_virtual_object(
+    source: &VirtualObject,
+    source_object: &Object,
+    derived: &VirtualObject,
+    previous: &Object,
+    actor: Option<&str>,
+    sem: &ImageSemaphore,
+    pool: &Pool,
+) -> Result<Object, String> {
+    let transforms = derived
+        .transforms
+        .as_deref()
+        .unwrap_or("")
+        .parse::<TransformationList>()?;
+    let (format, quality, options) = derivation_settings(previous);
+    let (object, _) = derive_transformed_image(
+        source_object,
+        Some(source),
+        transforms,
+        quality,
+        options,
+        format,
+        None,
+        sem,
+        pool,
+    )
+    .await?;
+    let conn = pool.get().map_err(|e| format!("{}", e))?;
+    let update = UpdateTransformedVirtualObject {
+        default_jpeg_bg: derived.default_jpeg_bg.clone(),
+        derived_virtual_object_id: Some(source.id),
+        primary_object_id: Some(object.id),
+        transforms: object.transforms.clone(),
+        transforms_hash: object.transforms_hash.clone(),
+    };
+    revise_virtual_object(&conn, derived, actor, "rederive", || {
+        replace_virtual_object_relations(&conn, std::slice::from_ref(&object), derived)?;
+        update_transformed_virtual_object(&conn, derived.id, update)
+    })?;
+    // Blur hashes are kept for the same components and background
+    for blur_hash in find_blur_hashes_for_objects(&conn, &[previous.id])? {
+        let background = Some(blur_hash.background).filter(|bg| !bg.is_empty());
+        create_blur_hash(
+            &object,
+            blur_hash.x_components,
+            blur_hash.y_components,
+            background,
+            sem,
+            pool,
+        )
+        .await?;
+    }
+    Ok(object)
+}
+
+// Derives every virtual object made from this one again from its current primary object,
+// followed by those derived from them. Returns the paths that were derived again
+// and those that could not be.
+pub async fn rederive_virtual_objects(
+    source: &VirtualObject,
+    actor: Option<&str>,
+    sem: &ImageSemaphore,
+    pool: &Pool,
+) -> Result<(Vec<String>, Vec<DerivationFailure>), String> {
+    let mut rederived = Vec::new();
+    let mut failed = Vec::new();
+    let source_object = {
+        let conn = pool.get().map_err(|e| format!("{}", e))?;
+        find_primary_object(&conn, source)?
+    };
+    // Each virtual object is derived again at most once, and never the one replaced
+    let mut visited = HashSet::from([source.id]);
+    let mut pending = match source_object {
+        Some(object) => vec![(source.clone(), object)],
+        None => return Ok((rederived, failed)),
+    };
+    while let Some((source, source_object)) = pending.pop() {
+        let derived = {
+            let conn = pool.get().map_err(|e| format!("{}", e))?;
+            find_derived_virtual_objects(&conn, &source)?
+        };
+        for (derived, previous) in derived {
+            if !visited.insert(derived.id) {
+                continue;
+            }
+            // Content that was not derived, or no longer matches how it was derived,
+            // was put there by hand and is left alone
+            if previous.derived_object_id.is_none()
+                || previous.transforms != derived.transforms
+                || previous.derived_object_id == Some(source_object.id)
+                || is_content_addressed(&derived.object_path, &previous.content_hash)
+            {
+                continue;
+            }
+            match rederive_virtual_object(
+                &source,
+                &source_object,
+                &derived,
+                &previous,
+                actor,
+                sem,
+                pool,
+            )
+            .await
+            {
+                Ok(object) => {
+                    println!(
+                        "Derived {} again from {}",
+                        derived.object_path, source.object_path
+                    );
+                    rederived.push(derived.object_path.clone());
+                    pending.push((derived, object));
+                }
+                Err(error) => {
+                    println!("Could not derive {} again: {}", derived.object_path, error);
+                    failed.push(DerivationFailure {
+                        path: derived.object_path,
+                        error,
+                    });
+                }
+            }
+        }
+    }
+    Ok((rederived, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_addressed_paths_are_skipped() {
+        let hash = "Zm9vYmFyYmF6cXV4cXV1eA";
+        assert!(is_content_addressed("Zm9vYmFyYm", hash));
+        assert!(is_content_addressed("Zm9vYmFyYmF6cXV4cXV1", hash));
+        assert!(!is_content_addressed("Zm9v", hash));
+        assert!(!is_content_addressed("photos/thumb.jpg", hash));
+    }
+
+    #[test]
+    fn requested_quality_is_derived_again() {
+        let mut object = Object::sample(1, "image/webp", 100, 100);
+        object.quality = Some(72);
+        assert_eq!(Some(Quality::Fixed(72)), derivation_settings(&object).1);
+        object.requested_quality = Some("auto_b50000".to_string());
+        assert_eq!(
+            Some("auto_b50000".to_string()),
+            derivation_settings(&object).1.map(|q| q.to_string())
+        );
+    }
+}
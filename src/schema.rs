@@ -31,6 +31,8 @@ table! {
         height -> Nullable<Integer>,
         content_headers -> Nullable<Text>,
         quality -> Nullable<Integer>,
+        encoder_options -> Nullable<Text>,
+        requested_quality -> Nullable<Text>,
     }
 }
 
@@ -44,6 +46,16 @@ table! {
     }
 }
 
+table! {
+    variant_recipe (id) {
+        id -> Integer,
+        path -> Text,
+        prefix -> Bool,
+        recipes -> Text,
+        modified -> BigInt,
+    }
+}
+
 table! {
     virtual_object (id) {
         id -> Integer,
@@ -53,6 +65,8 @@ table! {
         primary_object_id -> Nullable<Integer>,
         transforms -> Nullable<Text>,
         transforms_hash -> Nullable<Text>,
+        alias_virtual_object_id -> Nullable<Integer>,
+        redirect_status -> Nullable<Integer>,
     }
 }
 
@@ -63,14 +77,47 @@ table! {
     }
 }
 
+table! {
+    virtual_object_tag (virtual_object_id, tag) {
+        virtual_object_id -> Integer,
+        tag -> Text,
+    }
+}
+
+table! {
+    virtual_object_revision (id) {
+        id -> Integer,
+        virtual_object_id -> Integer,
+        created -> BigInt,
+        actor -> Nullable<Text>,
+        action -> Text,
+        primary_object_id -> Nullable<Integer>,
+    }
+}
+
+table! {
+    virtual_object_revision_object (revision_id, object_id) {
+        revision_id -> Integer,
+        object_id -> Integer,
+    }
+}
+
 joinable!(object_blur_hash -> object (object_id));
 joinable!(virtual_object -> object (primary_object_id));
 joinable!(virtual_object_relation -> object (object_id));
 joinable!(virtual_object_relation -> virtual_object (virtual_object_id));
+joinable!(virtual_object_revision -> virtual_object (virtual_object_id));
+joinable!(virtual_object_revision_object -> object (object_id));
+joinable!(virtual_object_revision_object -> virtual_object_revision (revision_id));
+joinable!(virtual_object_tag -> virtual_object (virtual_object_id));
 
 allow_tables_to_appear_in_same_query!(
     object,
     object_blur_hash,
+    variant_recipe,
     virtual_object,
     virtual_object_relation,
+    virtual_object_revision,
+    virtual_object_revision_object,
+    virtual_object_tag,
 );
@@ -0,0 +1,267 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use image::RgbaImage;
+use once_cell::sync::OnceCell;
+use roxmltree::{Document, Node, NodeType, ParsingOptions};
+use std::fmt::Write;
+
+// Declared sizes can be arbitrarily large, rendering is capped to this
+const SVG_MAX_DIMENSION: u32 = 4096;
+
+const NS_XML_URI: &str = "http://www.w3.org/XML/1998/namespace";
+
+static SVG_OPTIONS: OnceCell<usvg::Options> = OnceCell::new();
+
+fn svg_options() -> &'static usvg::Options {
+    SVG_OPTIONS.get_or_init(|| {
+        let mut options = usvg::Options::default();
+        // Never read local files referenced by the document
+        options.image_href_resolver.resolve_string = Box::new(|_, _| None);
+        options.fontdb.load_system_fonts();
+        println!("Loaded {} fonts for SVG text", options.fontdb.len());
+        options
+    })
+}
+
+// Renders the SVG to fit within the requested size, or at its own size
+pub fn blocking_svg_rasterize(data: &[u8], fit: Option<(u32, u32)>) -> Result<RgbaImage, String> {
+    let tree =
+        usvg::Tree::from_data(data, &svg_options().to_ref()).map_err(|e| format!("{}", e))?;
+    let original = tree.svg_node().size.to_screen_size();
+    let fit_to = match fit {
+        Some((w, h)) => usvg::FitTo::Size(w.min(SVG_MAX_DIMENSION), h.min(SVG_MAX_DIMENSION)),
+        None if original.width() > SVG_MAX_DIMENSION || original.height() > SVG_MAX_DIMENSION => {
+            usvg::FitTo::Size(SVG_MAX_DIMENSION, SVG_MAX_DIMENSION)
+        }
+        None => usvg::FitTo::Original,
+    };
+    let size = fit_to
+        .fit_to(original)
+        .ok_or_else(|| "SVG has an invalid size".to_string())?;
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or_else(|| "Could not allocate SVG canvas".to_string())?;
+    resvg::render(
+        &tree,
+        fit_to,
+        tiny_skia::Transform::default(),
+        pixmap.as_mut(),
+    )
+    .ok_or_else(|| "Could not render SVG".to_string())?;
+    let mut pixels = pixmap.take();
+    // The canvas is premultiplied, images are not
+    for pixel in pixels.chunks_exact_mut(4) {
+        let alpha = pixel[3] as u32;
+        if alpha > 0 && alpha < 255 {
+            for channel in &mut pixel[..3] {
+                *channel = ((*channel as u32 * 255 + alpha / 2) / alpha).min(255) as u8;
+            }
+        }
+    }
+    RgbaImage::from_raw(size.width(), size.height(), pixels)
+        .ok_or_else(|| "Could not copy SVG data".to_string())
+}
+
+fn is_forbidden_element(node: &Node) -> bool {
+    let name = node.tag_name().name().to_ascii_lowercase();
+    match name.as_str() {
+        "script" | "foreignobject" | "iframe" | "embed" | "object" | "handler" | "listener" => true,
+        // Animations can rewrite links and event handlers after sanitizing
+        "set" | "animate" | "animatemotion" | "animatetransform" => node
+            .attribute("attributeName")
+            .map(|target| {
+                let target = target.to_ascii_lowercase();
+                target.starts_with("on") || target.ends_with("href")
+            })
+            .unwrap_or(false),
+        // Stylesheets can import or load external resources
+        "style" => node
+            .text()
+            .map(|css| has_external_reference(css) || css.to_ascii_lowercase().contains("@import"))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn has_external_reference(value: &str) -> bool {
+    let lower = value.to_ascii_lowercase();
+    lower.match_indices("url(").any(|(index, _)| {
+        let target = lower[index + 4..]
+            .trim_start_matches(|c: char| c.is_whitespace() || c == '\'' || c == '"');
+        !target.starts_with('#')
+    })
+}
+
+fn is_allowed_attribute(name: &str, value: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    if name.starts_with("on") {
+        return false;
+    }
+    if name == "href" {
+        let value = value.trim().to_ascii_lowercase();
+        // Local fragments and embedded raster images are all that remain
+        return value.starts_with('#')
+            || (value.starts_with("data:image/") && !value.starts_with("data:image/svg"));
+    }
+    !has_external_reference(value)
+}
+
+fn escape_into(out: &mut String, text: &str, attribute: bool) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' if attribute => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn qualified_name(node: &Node, namespace: Option<&str>, name: &str, attribute: bool) -> String {
+    let prefix = match namespace {
+        None => None,
+        Some(NS_XML_URI) => Some("xml"),
+        Some(uri) => node
+            .namespaces()
+            .iter()
+            .filter(|ns| ns.uri() == uri)
+            // Attributes cannot use the default namespace
+            .find(|ns| !attribute || ns.name().is_some())
+            .and_then(|ns| ns.name()),
+    };
+    match prefix {
+        Some(prefix) => format!("{}:{}", prefix, name),
+        None => name.to_string(),
+    }
+}
+
+fn write_sanitized(out: &mut String, node: Node) -> Result<(), String> {
+    match node.node_type() {
+        NodeType::Root => {
+            for child in node.children() {
+                write_sanitized(out, child)?;
+            }
+        }
+        NodeType::Text => {
+            escape_into(out, node.text().unwrap_or(""), false);
+        }
+        // Comments and processing instructions are dropped
+        NodeType::Comment | NodeType::PI => {}
+        NodeType::Element => {
+            if is_forbidden_element(&node) {
+                println!("Removed SVG element {}", node.tag_name().name());
+                return Ok(());
+            }
+            let name = qualified_name(
+                &node,
+                node.tag_name().namespace(),
+                node.tag_name().name(),
+                false,
+            );
+            out.push('<');
+            out.push_str(&name);
+            // Only declare namespaces that the parent did not already have
+            let parent_namespaces = node
+                .parent_element()
+                .map(|parent| parent.namespaces())
+                .unwrap_or(&[]);
+            for ns in node.namespaces() {
+                if ns.uri() == NS_XML_URI || parent_namespaces.contains(ns) {
+                    continue;
+                }
+                match ns.name() {
+                    Some(prefix) => write!(out, " xmlns:{}=\"", prefix),
+                    None => write!(out, " xmlns=\""),
+                }
+                .map_err(|e| format!("{}", e))?;
+                escape_into(out, ns.uri(), true);
+                out.push('"');
+            }
+            for attribute in node.attributes() {
+                if !is_allowed_attribute(attribute.name(), attribute.value()) {
+                    println!("Removed SVG attribute {}", attribute.name());
+                    continue;
+                }
+                let attribute_name =
+                    qualified_name(&node, attribute.namespace(), attribute.name(), true);
+                write!(out, " {}=\"", attribute_name).map_err(|e| format!("{}", e))?;
+                escape_into(out, attribute.value(), true);
+                out.push('"');
+            }
+            if node.has_children() {
+                out.push('>');
+                for child in node.children() {
+                    write_sanitized(out, child)?;
+                }
+                write!(out, "</{}>", name).map_err(|e| format!("{}", e))?;
+            } else {
+                out.push_str("/>");
+            }
+        }
+    }
+    Ok(())
+}
+
+// Removes scripts, event handlers, and references to anything outside of the document
+pub fn sanitize_svg(data: &[u8]) -> Result<Vec<u8>, String> {
+    let text = std::str::from_utf8(data).map_err(|e| format!("{}", e))?;
+    let document = Document::parse_with_options(text, ParsingOptions { allow_dtd: true })
+        .map_err(|e| format!("{}", e))?;
+    if !document.root_element().has_tag_name("svg") {
+        return Err("Document is not an SVG".to_string());
+    }
+    let mut out = String::with_capacity(text.len());
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    write_sanitized(&mut out, document.root())?;
+    Ok(out.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitize(input: &str) -> String {
+        String::from_utf8(sanitize_svg(input.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn scripts_and_handlers_are_removed() {
+        let output = sanitize(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" onload="alert(1)"><script>alert(2)</script><rect width="1" height="1" onclick="alert(3)"/></svg>"#,
+        );
+        assert_eq!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\"><rect width=\"1\" height=\"1\"/></svg>",
+            output
+        );
+    }
+
+    #[test]
+    fn external_references_are_removed() {
+        let output = sanitize(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><use xlink:href="#a"/><image href="https://example.com/a.png"/><rect fill="url(http://example.com/#p)" stroke="url(#g)"/></svg>"##,
+        );
+        assert!(output.contains(r##"<use xlink:href="#a"/>"##));
+        assert!(output.contains("<image/>"));
+        assert!(output.contains(r##"<rect stroke="url(#g)"/>"##));
+        assert!(!output.contains("example.com"));
+    }
+
+    #[test]
+    fn non_svg_documents_are_rejected() {
+        assert!(sanitize_svg(b"<html><body/></html>").is_err());
+    }
+}
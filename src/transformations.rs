@@ -24,6 +24,7 @@ pub enum Transformation {
     Background(u32),
     Blur(f32),
     Crop(u32, u32, u32, u32),
+    Frame(u32),
     Noop,
 }
 
@@ -34,9 +35,50 @@ impl TransformationList {
     pub fn list(self) -> Vec<Transformation> {
         self.0
     }
+    pub fn list_ref(&self) -> &[Transformation] {
+        &self.0
+    }
     pub fn empty() -> TransformationList {
         TransformationList(Vec::with_capacity(0))
     }
+    // Multiplies resizes by the device pixel ratio without going past
+    // the source resolution, when it is known
+    pub fn with_density(self, dpr: f32, source: Option<(u32, u32)>) -> TransformationList {
+        TransformationList(
+            self.0
+                .into_iter()
+                .map(|t| match t {
+                    Transformation::Resize(w, h) => {
+                        let factor = match source {
+                            Some((source_w, source_h)) if w > 0 && h > 0 && dpr > 1.0 => {
+                                let cap = (source_w as f32 / w as f32)
+                                    .min(source_h as f32 / h as f32)
+                                    .max(1.0);
+                                dpr.min(cap)
+                            }
+                            _ => dpr,
+                        };
+                        Transformation::Resize(
+                            (w as f32 * factor).round() as u32,
+                            (h as f32 * factor).round() as u32,
+                        )
+                    }
+                    t => t,
+                })
+                .collect(),
+        )
+    }
+    // Adds a background unless one is already applied
+    pub fn with_background(mut self, color: u32) -> TransformationList {
+        if !self
+            .0
+            .iter()
+            .any(|t| matches!(t, Transformation::Background(_)))
+        {
+            self.0.push(Transformation::Background(color));
+        }
+        self
+    }
 }
 
 impl From<Vec<Transformation>> for TransformationList {
@@ -53,6 +95,7 @@ impl fmt::Display for Transformation {
             Transformation::Background(color) => write!(f, "bg{:06x}", color),
             Transformation::Blur(sigma) => write!(f, "bl{}", sigma),
             Transformation::Crop(x, y, w, h) => write!(f, "c{}_{}_{}_{}", x, y, w, h),
+            Transformation::Frame(index) => write!(f, "f{}", index),
             Transformation::Noop => write!(f, "id"),
         }
     }
@@ -155,6 +198,10 @@ impl FromStr for Transformation {
                 }
                 Err(format!("Could not parse {} into a transformation", s))
             }
+            Some('f') => {
+                let index = s[1..].parse::<u32>().map_err(|e| format!("{}", e))?;
+                Ok(Transformation::Frame(index))
+            }
             _ => Err(format!("Could not parse {} into a transformation", s)),
         }
     }
@@ -312,6 +359,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn frame_encodes_as_expected() {
+        assert_eq!("f0", Transformation::Frame(0).to_string());
+    }
+
+    #[test]
+    fn frame_decodes_as_expected() {
+        assert_eq!(Ok(Transformation::Frame(3)), "f3".parse::<Transformation>());
+    }
+
+    #[test]
+    fn density_scales_resize_up_to_source() {
+        let list = TransformationList(vec![
+            Transformation::Resize(100, 50),
+            Transformation::Blur(1.0),
+        ]);
+        assert_eq!(
+            "r200_100,bl1",
+            list.clone().with_density(2.0, None).to_string()
+        );
+        assert_eq!(
+            "r150_75,bl1",
+            list.clone().with_density(2.0, Some((150, 300))).to_string()
+        );
+        assert_eq!(
+            "r100_50,bl1",
+            list.clone().with_density(3.0, Some((80, 40))).to_string()
+        );
+        assert_eq!(
+            "r50_25,bl1",
+            list.with_density(0.5, Some((80, 40))).to_string()
+        );
+    }
+
     #[test]
     fn list_encodes_as_expected() {
         assert_eq!(
@@ -0,0 +1,266 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use std::collections::HashSet;
+use std::time::SystemTime;
+
+use crate::find_object::find_primary_object;
+use crate::image_operations::ImageSemaphore;
+use crate::models::{
+    DerivationFailure, DeriveTransformedObjectsRequestObject,
+    DeriveTransformedObjectsResponseObject, NewVariantRecipe, VariantRecipe, VirtualObject,
+};
+use crate::object_image::derive_variant;
+use crate::sqlite::Pool;
+
+// Builds a variant path from the uploaded path.
+// {path} is the whole path, {dir} is everything up to and including the last /,
+// {name} is the file name without its last extension and {ext} is that extension.
+pub fn expand_path_template(template: &str, path: &str) -> Result<String, String> {
+    let (dir, file) = match path.rfind('/') {
+        Some(index) => path.split_at(index + 1),
+        None => ("", path),
+    };
+    let (name, ext) = match file.rfind('.') {
+        Some(index) if index > 0 => (&file[..index], &file[index + 1..]),
+        _ => (file, ""),
+    };
+    let mut expanded = String::with_capacity(template.len() + path.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed {{ in \"{}\"", template))?;
+        match &rest[start + 1..start + end] {
+            "path" => expanded.push_str(path),
+            "dir" => expanded.push_str(dir),
+            "name" => expanded.push_str(name),
+            "ext" => expanded.push_str(ext),
+            other => {
+                return Err(format!(
+                    "Unknown placeholder {{{}}} in \"{}\"",
+                    other, template
+                ))
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    if expanded.is_empty() || expanded == path {
+        return Err(format!("\"{}\" would replace {}", template, path));
+    }
+    Ok(expanded)
+}
+
+// Recipes are checked when they are saved rather than on every upload
+pub fn parse_variant_recipes(
+    json: &str,
+) -> Result<Vec<DeriveTransformedObjectsRequestObject>, String> {
+    let recipes: Vec<DeriveTransformedObjectsRequestObject> =
+        serde_json::from_str(json).map_err(|err| format!("{}", err))?;
+    for recipe in &recipes {
+        expand_path_template(&recipe.path, "example/photo.jpg")?;
+    }
+    Ok(recipes)
+}
+
+fn matches_path(recipe: &VariantRecipe, path: &str) -> bool {
+    if recipe.prefix {
+        path.starts_with(&recipe.path)
+    } else {
+        path == recipe.path
+    }
+}
+
+// Exact paths first, then the longest prefix
+fn order_by_specificity(recipes: &mut [VariantRecipe]) {
+    recipes.sort_by(|a, b| {
+        a.prefix
+            .cmp(&b.prefix)
+            .then_with(|| b.path.len().cmp(&a.path.len()))
+    });
+}
+
+pub fn set_variant_recipes(
+    conn: &SqliteConnection,
+    path: &str,
+    prefix: bool,
+    json: &str,
+) -> Result<(), String> {
+    use crate::schema::variant_recipe;
+    parse_variant_recipes(json)?;
+    let modified = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|err| format!("{}", err))?
+        .as_secs() as i64;
+    diesel::replace_into(variant_recipe::table)
+        .values(NewVariantRecipe {
+            path: path.to_string(),
+            prefix,
+            recipes: json.to_string(),
+            modified,
+        })
+        .execute(conn)
+        .map_err(|err| format!("{}", err))?;
+    println!("Saved variant recipes for {} (prefix: {})", path, prefix);
+    Ok(())
+}
+
+pub fn remove_variant_recipes(
+    conn: &SqliteConnection,
+    path: &str,
+    prefix: bool,
+) -> Result<bool, String> {
+    use crate::schema::variant_recipe;
+    let count = diesel::delete(variant_recipe::table)
+        .filter(variant_recipe::path.eq(path))
+        .filter(variant_recipe::prefix.eq(prefix))
+        .execute(conn)
+        .map_err(|err| format!("{}", err))?;
+    Ok(count > 0)
+}
+
+// Most specific first
+pub fn find_variant_recipes_for_path(
+    conn: &SqliteConnection,
+    path: &str,
+) -> Result<Vec<VariantRecipe>, String> {
+    use crate::schema::variant_recipe;
+    // Prefixes are few, so they are matched here rather than in SQL
+    let mut recipes: Vec<VariantRecipe> = variant_recipe::table
+        .filter(
+            variant_recipe::path
+                .eq(path)
+                .or(variant_recipe::prefix.eq(true)),
+        )
+        .load::<VariantRecipe>(conn)
+        .map_err(|err| format!("{}", err))?
+        .into_iter()
+        .filter(|recipe| matches_path(recipe, path))
+        .collect();
+    order_by_specificity(&mut recipes);
+    Ok(recipes)
+}
+
+// Derives every variant that recipes ask for from the virtual object's primary object.
+// A recipe that fails is returned with the others and does not stop them.
+pub async fn apply_variant_recipes(
+    vobj: &VirtualObject,
+    actor: Option<&str>,
+    sem: &ImageSemaphore,
+    pool: &Pool,
+) -> Result<
+    (
+        Vec<DeriveTransformedObjectsResponseObject>,
+        Vec<DerivationFailure>,
+    ),
+    String,
+> {
+    let (recipes, object) = {
+        let conn = pool.get().map_err(|e| format!("{}", e))?;
+        let recipes = find_variant_recipes_for_path(&conn, &vobj.object_path)?;
+        if recipes.is_empty() {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        match find_primary_object(&conn, vobj)? {
+            Some(object) => (recipes, object),
+            None => return Ok((Vec::new(), Vec::new())),
+        }
+    };
+    let mut derived = Vec::new();
+    let mut failed = Vec::new();
+    let mut seen = HashSet::new();
+    for recipe in recipes {
+        for request in parse_variant_recipes(&recipe.recipes)? {
+            let path = match expand_path_template(&request.path, &vobj.object_path) {
+                Ok(path) => path,
+                Err(err) => {
+                    println!("Skipping recipe for {}: {}", vobj.object_path, err);
+                    continue;
+                }
+            };
+            // A more specific recipe already made this path
+            if !seen.insert(path.clone()) {
+                continue;
+            }
+            match derive_variant(&object, vobj, &request, &path, actor, sem, pool).await {
+                Ok(variant) => derived.push(variant),
+                Err(error) => {
+                    println!(
+                        "Could not derive {} from {}: {}",
+                        path, vobj.object_path, error
+                    );
+                    failed.push(DerivationFailure { path, error });
+                }
+            }
+        }
+    }
+    Ok((derived, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recipe(path: &str, prefix: bool) -> VariantRecipe {
+        VariantRecipe {
+            id: 0,
+            path: path.to_string(),
+            prefix,
+            recipes: "[]".to_string(),
+            modified: 0,
+        }
+    }
+
+    #[test]
+    fn templates_expand_from_the_uploaded_path() {
+        let path = "photos/2022/cat.jpg";
+        assert_eq!(
+            expand_path_template("{dir}{name}-thumb.webp", path).unwrap(),
+            "photos/2022/cat-thumb.webp"
+        );
+        assert_eq!(
+            expand_path_template("{path}/small.{ext}", path).unwrap(),
+            "photos/2022/cat.jpg/small.jpg"
+        );
+        assert_eq!(
+            expand_path_template("thumbs/{name}", "cat").unwrap(),
+            "thumbs/cat"
+        );
+        assert!(expand_path_template("{path}", path).is_err());
+        assert!(expand_path_template("{size}.jpg", path).is_err());
+        assert!(expand_path_template("{name", path).is_err());
+    }
+
+    #[test]
+    fn specific_recipes_come_first() {
+        let mut recipes = vec![
+            recipe("photos/", true),
+            recipe("photos/2022/", true),
+            recipe("photos/2022/cat.jpg", false),
+        ];
+        assert!(recipes
+            .iter()
+            .all(|r| matches_path(r, "photos/2022/cat.jpg")));
+        assert!(!matches_path(&recipes[2], "photos/2022/dog.jpg"));
+        order_by_specificity(&mut recipes);
+        let paths: Vec<&str> = recipes.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, ["photos/2022/cat.jpg", "photos/2022/", "photos/"]);
+    }
+}
@@ -24,6 +24,14 @@ use crate::models::{
 };
 use crate::sqlite::last_insert_rowid;
 
+// Shortest path given to a virtual object named after its content hash
+const CONTENT_ADDRESSED_PATH_LENGTH: usize = 10;
+
+// Virtual objects named after their content always refer to that content
+pub(crate) fn is_content_addressed(path: &str, content_hash: &str) -> bool {
+    path.len() >= CONTENT_ADDRESSED_PATH_LENGTH && content_hash.starts_with(path)
+}
+
 pub fn find_virtual_object_by_object_path(
     conn: &SqliteConnection,
     path: &str,
@@ -37,19 +45,111 @@ pub fn find_virtual_object_by_object_path(
     Ok(result)
 }
 
-pub fn find_virtual_object_by_object_paths(
+pub fn find_virtual_object_by_id(
     conn: &SqliteConnection,
-    paths: &[&str],
+    vobj_id: i32,
 ) -> Result<Option<VirtualObject>, String> {
     use crate::schema::virtual_object::dsl::*;
     let result = virtual_object
-        .filter(object_path.eq_any(paths))
+        .filter(id.eq(vobj_id))
         .first(conn)
         .optional()
         .map_err(|err| format!("{}", err))?;
     Ok(result)
 }
 
+pub const MAX_ALIAS_DEPTH: usize = 8;
+pub const REDIRECT_STATUSES: [i32; 4] = [301, 302, 307, 308];
+
+#[derive(Debug)]
+pub enum AliasResolution {
+    // Serve this virtual object
+    Target(VirtualObject),
+    // Redirect with this status to the final virtual object
+    Redirect(i32, VirtualObject),
+}
+
+// Follows aliases to the virtual object that holds content,
+// redirects always point at the end of the chain
+pub fn resolve_alias(
+    conn: &SqliteConnection,
+    vobj: VirtualObject,
+) -> Result<AliasResolution, String> {
+    let redirect_status = vobj.alias_virtual_object_id.and(vobj.redirect_status);
+    let mut current = vobj;
+    let mut depth = 0;
+    while let Some(alias_id) = current.alias_virtual_object_id {
+        depth += 1;
+        if depth > MAX_ALIAS_DEPTH {
+            return Err(format!("Too many aliases from {}", current.object_path));
+        }
+        current = find_virtual_object_by_id(conn, alias_id)?
+            .ok_or_else(|| format!("Alias of {} does not exist", current.object_path))?;
+    }
+    Ok(match redirect_status {
+        Some(status) => AliasResolution::Redirect(status, current),
+        None => AliasResolution::Target(current),
+    })
+}
+
+pub fn set_virtual_object_alias(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+    target: Option<&VirtualObject>,
+    redirect_status: Option<i32>,
+) -> Result<(), String> {
+    use crate::schema::virtual_object;
+    if let Some(status) = redirect_status {
+        if !REDIRECT_STATUSES.contains(&status) {
+            return Err(format!("{} is not a redirect status", status));
+        }
+    }
+    if let Some(target) = target {
+        // The target cannot lead back here
+        let mut current = Some(target.id);
+        let mut depth = 0;
+        while let Some(id) = current {
+            if id == vobj.id {
+                return Err(format!("{} would alias itself", vobj.object_path));
+            }
+            depth += 1;
+            if depth > MAX_ALIAS_DEPTH {
+                return Err(format!("Too many aliases from {}", target.object_path));
+            }
+            current = find_virtual_object_by_id(conn, id)?.and_then(|v| v.alias_virtual_object_id);
+        }
+    }
+    let count = diesel::update(virtual_object::table)
+        .set((
+            virtual_object::alias_virtual_object_id.eq(target.map(|target| target.id)),
+            virtual_object::redirect_status.eq(target.and(redirect_status)),
+        ))
+        .filter(virtual_object::id.eq(vobj.id))
+        .execute(conn)
+        .map_err(|err| format!("{}", err))?;
+    println!("Updated {}", count);
+    Ok(())
+}
+
+pub fn find_virtual_object_by_object_paths(
+    conn: &SqliteConnection,
+    paths: &[&str],
+) -> Result<Option<VirtualObject>, String> {
+    use crate::schema::virtual_object::dsl::*;
+    let found: Vec<VirtualObject> = virtual_object
+        .filter(object_path.eq_any(paths))
+        .load(conn)
+        .map_err(|err| format!("{}", err))?;
+    // Paths are given most specific first
+    let result = found.into_iter().min_by_key(|vobj| {
+        paths
+            .iter()
+            .position(|path| *path == vobj.object_path)
+            .unwrap_or(paths.len())
+    });
+    Ok(result)
+}
+
 struct VirtualObjectError;
 
 impl diesel::result::DatabaseErrorInformation for VirtualObjectError {
@@ -93,6 +193,8 @@ pub fn find_or_create_virtual_object_by_object_path(
                         primary_object_id: None,
                         transforms: None,
                         transforms_hash: None,
+                        alias_virtual_object_id: None,
+                        redirect_status: None,
                     })
                     .execute(conn)?;
                 if result > 0 {
@@ -134,6 +236,51 @@ pub fn find_related_objects_to_virtual_object(
     Ok(result)
 }
 
+// Virtual objects made by transforming this one, along with their primary object
+pub fn find_derived_virtual_objects(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+) -> Result<Vec<(VirtualObject, Object)>, String> {
+    use crate::schema::virtual_object;
+    let result = virtual_object::table
+        .inner_join(crate::schema::object::table)
+        .filter(virtual_object::derived_virtual_object_id.eq(vobj.id))
+        .order(virtual_object::id.asc())
+        .load(conn)
+        .map_err(|err| format!("{}", err))?;
+    Ok(result)
+}
+
+// Related objects filtered in the query, encodings are every stored form of one encoding
+pub fn find_related_objects_matching(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+    content_type: Option<&str>,
+    content_encodings: Option<&[&str]>,
+    size: Option<(i32, i32)>,
+) -> Result<Vec<Object>, String> {
+    use crate::schema::object;
+    use crate::schema::virtual_object_relation;
+    let mut query = virtual_object_relation::table
+        .inner_join(object::table)
+        .filter(virtual_object_relation::virtual_object_id.eq(vobj.id))
+        .select(object::all_columns)
+        .into_boxed();
+    if let Some(content_type) = content_type {
+        query = query.filter(object::content_type.eq(content_type));
+    }
+    if let Some(content_encodings) = content_encodings {
+        query = query.filter(object::content_encoding.eq_any(content_encodings));
+    }
+    if let Some((width, height)) = size {
+        query = query
+            .filter(object::width.eq(width))
+            .filter(object::height.eq(height));
+    }
+    let result = query.load(conn).map_err(|err| format!("{}", err))?;
+    Ok(result)
+}
+
 /* TODO use around insertion
 conn.transaction::<_, diesel::result::Error, _>(|| {
     delete(opts, &conn);
@@ -229,6 +376,21 @@ pub fn update_transformed_virtual_object(
     Ok(())
 }
 
+pub fn set_default_jpeg_bg(
+    conn: &SqliteConnection,
+    id: i32,
+    default_jpeg_bg: Option<String>,
+) -> Result<(), String> {
+    use crate::schema::virtual_object;
+    let count = diesel::update(virtual_object::table)
+        .set(virtual_object::default_jpeg_bg.eq(default_jpeg_bg))
+        .filter(virtual_object::id.eq(&id))
+        .execute(conn)
+        .map_err(|err| format!("{}", err))?;
+    println!("Updated {}", count);
+    Ok(())
+}
+
 pub fn set_primary_object(conn: &SqliteConnection, id: i32, object_id: i32) -> Result<(), String> {
     use crate::schema::virtual_object;
     let count = diesel::update(virtual_object::table)
@@ -240,6 +402,75 @@ pub fn set_primary_object(conn: &SqliteConnection, id: i32, object_id: i32) -> R
     Ok(())
 }
 
+// Content set directly is no longer derived, so it is not derived again
+pub fn clear_derived_virtual_object(conn: &SqliteConnection, id: i32) -> Result<(), String> {
+    use crate::schema::virtual_object;
+    diesel::update(virtual_object::table)
+        .set(virtual_object::derived_virtual_object_id.eq(None::<i32>))
+        .filter(virtual_object::id.eq(&id))
+        .execute(conn)
+        .map_err(|err| format!("{}", err))?;
+    Ok(())
+}
+
+pub fn delete_virtual_object(conn: &SqliteConnection, vobj: &VirtualObject) -> Result<(), String> {
+    conn.transaction(|| delete_virtual_object_rows(conn, &[vobj.id]))
+        .map_err(|err| format!("{}", err))?;
+    println!("Deleted virtual object {}", vobj.object_path);
+    Ok(())
+}
+
+// Deletes these virtual objects with their relations, revisions and tags, and unlinks
+// what was derived from or aliased to them. Related objects are left for garbage collection.
+pub(crate) fn delete_virtual_object_rows(conn: &SqliteConnection, ids: &[i32]) -> QueryResult<()> {
+    use crate::schema::{
+        virtual_object, virtual_object_relation, virtual_object_revision,
+        virtual_object_revision_object, virtual_object_tag,
+    };
+    diesel::delete(virtual_object_relation::table)
+        .filter(virtual_object_relation::virtual_object_id.eq_any(ids))
+        .execute(conn)?;
+    let revisions = virtual_object_revision::table
+        .filter(virtual_object_revision::virtual_object_id.eq_any(ids))
+        .select(virtual_object_revision::id);
+    diesel::delete(virtual_object_revision_object::table)
+        .filter(virtual_object_revision_object::revision_id.eq_any(revisions))
+        .execute(conn)?;
+    diesel::delete(virtual_object_revision::table)
+        .filter(virtual_object_revision::virtual_object_id.eq_any(ids))
+        .execute(conn)?;
+    diesel::delete(virtual_object_tag::table)
+        .filter(virtual_object_tag::virtual_object_id.eq_any(ids))
+        .execute(conn)?;
+    diesel::update(virtual_object::table)
+        .set(virtual_object::derived_virtual_object_id.eq(None::<i32>))
+        .filter(virtual_object::derived_virtual_object_id.eq_any(ids))
+        .execute(conn)?;
+    diesel::update(virtual_object::table)
+        .set((
+            virtual_object::alias_virtual_object_id.eq(None::<i32>),
+            virtual_object::redirect_status.eq(None::<i32>),
+        ))
+        .filter(virtual_object::alias_virtual_object_id.eq_any(ids))
+        .execute(conn)?;
+    diesel::delete(virtual_object::table)
+        .filter(virtual_object::id.eq_any(ids))
+        .execute(conn)?;
+    Ok(())
+}
+
+// Without a primary object the dominant object is used
+pub fn clear_primary_object(conn: &SqliteConnection, id: i32) -> Result<(), String> {
+    use crate::schema::virtual_object;
+    let count = diesel::update(virtual_object::table)
+        .set(virtual_object::primary_object_id.eq(None::<i32>))
+        .filter(virtual_object::id.eq(&id))
+        .execute(conn)
+        .map_err(|err| format!("{}", err))?;
+    println!("Updated {}", count);
+    Ok(())
+}
+
 pub fn set_primary_object_if_none(
     conn: &SqliteConnection,
     id: i32,
@@ -0,0 +1,209 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::models::VirtualObject;
+
+pub const DEFAULT_LIST_PAGE: i64 = 100;
+pub const MAX_LIST_PAGE: i64 = 1000;
+// Rows read after skipping a group, groups tend to be followed by more groups
+const AFTER_PREFIX_BATCH: i64 = 32;
+
+// Sorts after any path that starts with the same characters
+pub(crate) const PATH_MAX_CHAR: char = '\u{10FFFF}';
+
+#[derive(Debug)]
+pub enum ListingEntry {
+    Object(VirtualObject),
+    // Paths sharing everything up to and including the delimiter
+    Prefix(String),
+}
+
+impl ListingEntry {
+    fn key(&self) -> &str {
+        match self {
+            ListingEntry::Object(vobj) => &vobj.object_path,
+            ListingEntry::Prefix(prefix) => prefix,
+        }
+    }
+
+    fn is_prefix(&self) -> bool {
+        matches!(self, ListingEntry::Prefix(_))
+    }
+
+    // Objects may end with the delimiter too, so the cursor says which it is
+    fn cursor(&self) -> String {
+        if self.is_prefix() {
+            format!("p:{}", self.key())
+        } else {
+            format!("o:{}", self.key())
+        }
+    }
+}
+
+// The key and whether it is a prefix
+fn parse_cursor(cursor: &str) -> Result<(&str, bool), String> {
+    if let Some(key) = cursor.strip_prefix("p:") {
+        Ok((key, true))
+    } else if let Some(key) = cursor.strip_prefix("o:") {
+        Ok((key, false))
+    } else {
+        Err(format!("Invalid cursor {}", cursor))
+    }
+}
+
+// The "directory" a path belongs to when grouping by delimiter
+pub fn common_prefix(path: &str, prefix: &str, delimiter: Option<&str>) -> Option<String> {
+    let delimiter = delimiter.filter(|delimiter| !delimiter.is_empty())?;
+    let rest = path.strip_prefix(prefix)?;
+    rest.find(delimiter)
+        .map(|index| path[..prefix.len() + index + delimiter.len()].to_string())
+}
+
+// Where to continue from after a key, prefixes skip every path under them
+fn continue_after(key: &str, is_prefix: bool, descending: bool) -> String {
+    if is_prefix && !descending {
+        format!("{}{}", key, PATH_MAX_CHAR)
+    } else {
+        key.to_string()
+    }
+}
+
+fn find_page(
+    conn: &SqliteConnection,
+    prefix: &str,
+    after: Option<&str>,
+    descending: bool,
+    limit: i64,
+) -> Result<Vec<VirtualObject>, String> {
+    use crate::schema::virtual_object::dsl::*;
+    // A range rather than LIKE, which is case insensitive and cannot use the index
+    let mut query = virtual_object
+        .filter(object_path.ge(prefix.to_string()))
+        .into_boxed();
+    if !prefix.is_empty() {
+        query = query.filter(object_path.lt(format!("{}{}", prefix, PATH_MAX_CHAR)));
+    }
+    query = match (after, descending) {
+        (Some(after), false) => query.filter(object_path.gt(after.to_string())),
+        (Some(after), true) => query.filter(object_path.lt(after.to_string())),
+        (None, _) => query,
+    };
+    query = if descending {
+        query.order(object_path.desc())
+    } else {
+        query.order(object_path.asc())
+    };
+    query
+        .limit(limit)
+        .load(conn)
+        .map_err(|err| format!("{}", err))
+}
+
+// Lists up to `limit` objects and prefixes, with the cursor for the next page
+pub fn list_virtual_objects(
+    conn: &SqliteConnection,
+    prefix: &str,
+    delimiter: Option<&str>,
+    cursor: Option<&str>,
+    limit: i64,
+    descending: bool,
+) -> Result<(Vec<ListingEntry>, Option<String>), String> {
+    let mut entries: Vec<ListingEntry> = Vec::new();
+    let mut after = match cursor {
+        Some(cursor) => {
+            let (key, is_prefix) = parse_cursor(cursor)?;
+            Some(continue_after(key, is_prefix, descending))
+        }
+        None => None,
+    };
+    let mut batch = limit + 1;
+    loop {
+        let page = find_page(conn, prefix, after.as_deref(), descending, batch)?;
+        let exhausted = (page.len() as i64) < batch;
+        let mut skipped = false;
+        for vobj in page {
+            let entry = match common_prefix(&vobj.object_path, prefix, delimiter) {
+                Some(group) => ListingEntry::Prefix(group),
+                None => ListingEntry::Object(vobj),
+            };
+            if entries.len() as i64 == limit {
+                let next = entries.last().map(ListingEntry::cursor);
+                return Ok((entries, next));
+            }
+            after = Some(continue_after(entry.key(), entry.is_prefix(), descending));
+            let is_prefix = entry.is_prefix();
+            entries.push(entry);
+            if is_prefix {
+                // Query again from past the group instead of reading every path in it
+                skipped = true;
+                break;
+            }
+        }
+        if exhausted && !skipped {
+            return Ok((entries, None));
+        }
+        // One more row than is left tells whether there is another page
+        let remaining = limit - entries.len() as i64 + 1;
+        batch = if skipped {
+            remaining.min(AFTER_PREFIX_BATCH)
+        } else {
+            remaining
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_group_by_delimiter() {
+        assert_eq!(
+            Some("products/123/".to_string()),
+            common_prefix("products/123/front.jpg", "products/", Some("/"))
+        );
+        assert_eq!(
+            None,
+            common_prefix("products/front.jpg", "products/", Some("/"))
+        );
+        assert_eq!(None, common_prefix("products/123/a", "products/", None));
+        assert_eq!(None, common_prefix("other/a/b", "products/", Some("/")));
+    }
+
+    #[test]
+    fn prefixes_are_skipped_when_continuing() {
+        assert_eq!(
+            format!("a/{}", PATH_MAX_CHAR),
+            continue_after("a/", true, false)
+        );
+        assert_eq!("a/", continue_after("a/", true, true));
+        assert_eq!("a.jpg", continue_after("a.jpg", false, false));
+        // An object named like a group does not skip the paths after it
+        assert_eq!("a/", continue_after("a/", false, false));
+    }
+
+    #[test]
+    fn cursors_record_the_entry_kind() {
+        let group = ListingEntry::Prefix("foo/".to_string());
+        assert_eq!("p:foo/", group.cursor());
+        assert_eq!(Ok(("foo/", true)), parse_cursor("p:foo/"));
+        assert_eq!(Ok(("foo/", false)), parse_cursor("o:foo/"));
+        assert!(parse_cursor("foo/").is_err());
+    }
+}
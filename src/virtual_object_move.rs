@@ -0,0 +1,218 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use std::collections::HashSet;
+
+use crate::models::{
+    NewVirtualObject, NewVirtualObjectTag, ReplaceVirtualObjectRelation, VirtualObject,
+};
+use crate::virtual_object::{find_virtual_object_by_object_path, REDIRECT_STATUSES};
+use crate::virtual_object_listing::PATH_MAX_CHAR;
+
+// Where a path under `from` ends up once moved to `to`
+pub fn renamed_path(path: &str, from: &str, to: &str) -> Option<String> {
+    path.strip_prefix(from)
+        .map(|rest| format!("{}{}", to, rest))
+}
+
+fn check_move(from: &str, to: &str, prefix: bool) -> Result<(), String> {
+    if from.is_empty() || to.is_empty() {
+        return Err("Paths cannot be empty".to_string());
+    }
+    if from == to {
+        return Err(format!("{} is already at {}", from, to));
+    }
+    // Renames would otherwise land on paths that have yet to move
+    if prefix && (to.starts_with(from) || from.starts_with(to)) {
+        return Err(format!("{} and {} overlap", from, to));
+    }
+    Ok(())
+}
+
+fn find_virtual_objects_by_prefix(
+    conn: &SqliteConnection,
+    prefix: &str,
+) -> Result<Vec<VirtualObject>, String> {
+    use crate::schema::virtual_object::dsl::*;
+    virtual_object
+        .filter(object_path.ge(prefix.to_string()))
+        .filter(object_path.lt(format!("{}{}", prefix, PATH_MAX_CHAR)))
+        .order(object_path.asc())
+        .load::<VirtualObject>(conn)
+        .map_err(|err| format!("{}", err))
+}
+
+// Renames a path, or every path under a prefix, in one transaction.
+// With a redirect status the old paths become aliases of the new ones.
+pub fn move_virtual_objects(
+    conn: &SqliteConnection,
+    from: &str,
+    to: &str,
+    prefix: bool,
+    redirect: Option<i32>,
+) -> Result<Vec<(String, String)>, String> {
+    check_move(from, to, prefix)?;
+    if let Some(status) = redirect {
+        if !REDIRECT_STATUSES.contains(&status) {
+            return Err(format!("{} is not a redirect status", status));
+        }
+    }
+    let (sources, existing) = if prefix {
+        (
+            find_virtual_objects_by_prefix(conn, from)?,
+            find_virtual_objects_by_prefix(conn, to)?,
+        )
+    } else {
+        (
+            find_virtual_object_by_object_path(conn, from)?
+                .into_iter()
+                .collect(),
+            find_virtual_object_by_object_path(conn, to)?
+                .into_iter()
+                .collect(),
+        )
+    };
+    if sources.is_empty() {
+        return Err(format!("Nothing to move at {}", from));
+    }
+    let existing: HashSet<String> = existing.into_iter().map(|v| v.object_path).collect();
+    let moves: Vec<(VirtualObject, String)> = sources
+        .into_iter()
+        .filter_map(|vobj| renamed_path(&vobj.object_path, from, to).map(|path| (vobj, path)))
+        .collect();
+    if let Some((_, path)) = moves.iter().find(|(_, path)| existing.contains(path)) {
+        return Err(format!("{} already exists", path));
+    }
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        use crate::schema::virtual_object;
+        for (vobj, path) in &moves {
+            diesel::update(virtual_object::table)
+                .set(virtual_object::object_path.eq(path))
+                .filter(virtual_object::id.eq(vobj.id))
+                .execute(conn)?;
+            if redirect.is_some() {
+                diesel::insert_into(virtual_object::table)
+                    .values(NewVirtualObject {
+                        object_path: vobj.object_path.clone(),
+                        default_jpeg_bg: None,
+                        derived_virtual_object_id: None,
+                        primary_object_id: None,
+                        transforms: None,
+                        transforms_hash: None,
+                        alias_virtual_object_id: Some(vobj.id),
+                        redirect_status: redirect,
+                    })
+                    .execute(conn)?;
+            }
+        }
+        Ok(())
+    })
+    .map_err(|err| format!("{}", err))?;
+    println!(
+        "Moved {} virtual objects from {} to {}",
+        moves.len(),
+        from,
+        to
+    );
+    Ok(moves
+        .into_iter()
+        .map(|(vobj, path)| (vobj.object_path, path))
+        .collect())
+}
+
+// Objects are shared, only the relations, tags and settings are copied
+pub fn copy_virtual_object(
+    conn: &SqliteConnection,
+    source: &VirtualObject,
+    to: &str,
+) -> Result<VirtualObject, String> {
+    use crate::schema::{virtual_object, virtual_object_relation, virtual_object_tag};
+    if to.is_empty() {
+        return Err("Paths cannot be empty".to_string());
+    }
+    if find_virtual_object_by_object_path(conn, to)?.is_some() {
+        return Err(format!("{} already exists", to));
+    }
+    let copy = conn
+        .transaction::<_, diesel::result::Error, _>(|| {
+            diesel::insert_into(virtual_object::table)
+                .values(NewVirtualObject {
+                    object_path: to.to_string(),
+                    default_jpeg_bg: source.default_jpeg_bg.clone(),
+                    derived_virtual_object_id: source.derived_virtual_object_id,
+                    primary_object_id: source.primary_object_id,
+                    transforms: source.transforms.clone(),
+                    transforms_hash: source.transforms_hash.clone(),
+                    alias_virtual_object_id: source.alias_virtual_object_id,
+                    redirect_status: source.redirect_status,
+                })
+                .execute(conn)?;
+            let copy = virtual_object::table
+                .filter(virtual_object::object_path.eq(to))
+                .first::<VirtualObject>(conn)?;
+            let relations: Vec<ReplaceVirtualObjectRelation> = virtual_object_relation::table
+                .filter(virtual_object_relation::virtual_object_id.eq(source.id))
+                .select(virtual_object_relation::object_id)
+                .load::<i32>(conn)?
+                .into_iter()
+                .map(|object_id| ReplaceVirtualObjectRelation {
+                    virtual_object_id: copy.id,
+                    object_id,
+                })
+                .collect();
+            diesel::insert_into(virtual_object_relation::table)
+                .values(relations)
+                .execute(conn)?;
+            let tags: Vec<NewVirtualObjectTag> = virtual_object_tag::table
+                .filter(virtual_object_tag::virtual_object_id.eq(source.id))
+                .select(virtual_object_tag::tag)
+                .load::<String>(conn)?
+                .into_iter()
+                .map(|tag| NewVirtualObjectTag {
+                    virtual_object_id: copy.id,
+                    tag,
+                })
+                .collect();
+            diesel::insert_into(virtual_object_tag::table)
+                .values(tags)
+                .execute(conn)?;
+            Ok(copy)
+        })
+        .map_err(|err| format!("{}", err))?;
+    println!("Copied {} to {}", source.object_path, copy.object_path);
+    Ok(copy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_move_without_overlap() {
+        assert_eq!(
+            renamed_path("photos/2022/cat.jpg", "photos/", "archive/"),
+            Some("archive/2022/cat.jpg".to_string())
+        );
+        assert_eq!(renamed_path("other/cat.jpg", "photos/", "archive/"), None);
+        assert!(check_move("photos/", "archive/", true).is_ok());
+        assert!(check_move("photos/", "photos/old/", true).is_err());
+        assert!(check_move("photos/old/", "photos/", true).is_err());
+        assert!(check_move("cat", "cat.jpg", false).is_ok());
+        assert!(check_move("cat", "cat", false).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use crate::models::{
+    NewVirtualObjectRevision, NewVirtualObjectRevisionObject, Object, ReplaceVirtualObjectRelation,
+    VirtualObject, VirtualObjectRevision,
+};
+
+pub const DEFAULT_REVISION_RETENTION: usize = 20;
+
+// How many revisions each virtual object keeps, older ones are pruned
+pub fn revision_retention() -> usize {
+    std::env::var("REVISION_RETENTION")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .filter(|retention| *retention > 0)
+        .unwrap_or(DEFAULT_REVISION_RETENTION)
+}
+
+// What a revision records, compared to skip revisions that change nothing
+#[derive(Debug, PartialEq, Eq)]
+pub struct RevisionState {
+    pub primary_object_id: Option<i32>,
+    pub object_ids: Vec<i32>,
+}
+
+impl RevisionState {
+    pub fn new(primary_object_id: Option<i32>, mut object_ids: Vec<i32>) -> Self {
+        object_ids.sort_unstable();
+        object_ids.dedup();
+        RevisionState {
+            primary_object_id,
+            object_ids,
+        }
+    }
+}
+
+fn current_state(
+    conn: &SqliteConnection,
+    vobj_id: i32,
+) -> Result<RevisionState, diesel::result::Error> {
+    use crate::schema::{virtual_object, virtual_object_relation};
+    // The caller's copy may predate the change being recorded
+    let primary_object_id = virtual_object::table
+        .filter(virtual_object::id.eq(vobj_id))
+        .select(virtual_object::primary_object_id)
+        .first::<Option<i32>>(conn)?;
+    let object_ids = virtual_object_relation::table
+        .filter(virtual_object_relation::virtual_object_id.eq(vobj_id))
+        .select(virtual_object_relation::object_id)
+        .load::<i32>(conn)?;
+    Ok(RevisionState::new(primary_object_id, object_ids))
+}
+
+fn revision_state(
+    conn: &SqliteConnection,
+    revision: &VirtualObjectRevision,
+) -> Result<RevisionState, diesel::result::Error> {
+    use crate::schema::virtual_object_revision_object;
+    let object_ids = virtual_object_revision_object::table
+        .filter(virtual_object_revision_object::revision_id.eq(revision.id))
+        .select(virtual_object_revision_object::object_id)
+        .load::<i32>(conn)?;
+    Ok(RevisionState::new(revision.primary_object_id, object_ids))
+}
+
+// Ids past the newest `keep`, given newest first
+fn revisions_past_retention(ids: &[i32], keep: usize) -> &[i32] {
+    if ids.len() > keep {
+        &ids[keep..]
+    } else {
+        &[]
+    }
+}
+
+fn prune_revisions(
+    conn: &SqliteConnection,
+    vobj_id: i32,
+    keep: usize,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::{virtual_object_revision, virtual_object_revision_object};
+    let ids = virtual_object_revision::table
+        .filter(virtual_object_revision::virtual_object_id.eq(vobj_id))
+        .order(virtual_object_revision::id.desc())
+        .select(virtual_object_revision::id)
+        .load::<i32>(conn)?;
+    let expired = revisions_past_retention(&ids, keep);
+    if expired.is_empty() {
+        return Ok(0);
+    }
+    diesel::delete(virtual_object_revision_object::table)
+        .filter(virtual_object_revision_object::revision_id.eq_any(expired))
+        .execute(conn)?;
+    diesel::delete(virtual_object_revision::table)
+        .filter(virtual_object_revision::id.eq_any(expired))
+        .execute(conn)
+}
+
+// Records the current relations and primary object, unless the latest revision already has them.
+// Calling this before a change also captures anything changed without a revision.
+pub fn record_revision(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+    actor: Option<&str>,
+    action: &str,
+) -> Result<Option<i32>, String> {
+    use crate::schema::{virtual_object_revision, virtual_object_revision_object};
+    let created = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|err| format!("{}", err))?
+        .as_secs() as i64;
+    let recorded = conn
+        .transaction::<_, diesel::result::Error, _>(|| {
+            let state = current_state(conn, vobj.id)?;
+            let latest = virtual_object_revision::table
+                .filter(virtual_object_revision::virtual_object_id.eq(vobj.id))
+                .order(virtual_object_revision::id.desc())
+                .first::<VirtualObjectRevision>(conn)
+                .optional()?;
+            let unchanged = match &latest {
+                Some(revision) => revision_state(conn, revision)? == state,
+                // Nothing worth remembering yet
+                None => state.object_ids.is_empty() && state.primary_object_id.is_none(),
+            };
+            if unchanged {
+                return Ok(None);
+            }
+            diesel::insert_into(virtual_object_revision::table)
+                .values(NewVirtualObjectRevision {
+                    virtual_object_id: vobj.id,
+                    created,
+                    actor: actor.map(|actor| actor.to_string()),
+                    action: action.to_string(),
+                    primary_object_id: state.primary_object_id,
+                })
+                .execute(conn)?;
+            let revision_id = virtual_object_revision::table
+                .filter(virtual_object_revision::virtual_object_id.eq(vobj.id))
+                .order(virtual_object_revision::id.desc())
+                .select(virtual_object_revision::id)
+                .first::<i32>(conn)?;
+            let objects: Vec<NewVirtualObjectRevisionObject> = state
+                .object_ids
+                .iter()
+                .map(|object_id| NewVirtualObjectRevisionObject {
+                    revision_id,
+                    object_id: *object_id,
+                })
+                .collect();
+            diesel::insert_into(virtual_object_revision_object::table)
+                .values(objects)
+                .execute(conn)?;
+            prune_revisions(conn, vobj.id, revision_retention())?;
+            Ok(Some(revision_id))
+        })
+        .map_err(|err| format!("{}", err))?;
+    if let Some(revision_id) = recorded {
+        println!(
+            "Recorded revision {} of {} for {}",
+            revision_id, vobj.object_path, action
+        );
+    }
+    Ok(recorded)
+}
+
+// A change made inside revise_virtual_object fails with its own message
+enum ReviseError {
+    Query(diesel::result::Error),
+    Change(String),
+}
+
+impl From<diesel::result::Error> for ReviseError {
+    fn from(err: diesel::result::Error) -> Self {
+        ReviseError::Query(err)
+    }
+}
+
+// Makes a change and records it as one revision in the same transaction, so no other
+// change comes between them. Whatever changed since the latest revision without one
+// is recorded first, without an actor as it is not known who made it.
+pub fn revise_virtual_object<F>(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+    actor: Option<&str>,
+    action: &str,
+    change: F,
+) -> Result<Option<i32>, String>
+where
+    F: FnOnce() -> Result<(), String>,
+{
+    conn.transaction::<_, ReviseError, _>(|| {
+        record_revision(conn, vobj, None, "snapshot").map_err(ReviseError::Change)?;
+        change().map_err(ReviseError::Change)?;
+        record_revision(conn, vobj, actor, action).map_err(ReviseError::Change)
+    })
+    .map_err(|err| match err {
+        ReviseError::Query(err) => format!("{}", err),
+        ReviseError::Change(err) => err,
+    })
+}
+
+// Newest first, with the objects each revision related to
+pub fn find_revisions(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+) -> Result<Vec<(VirtualObjectRevision, Vec<Object>)>, String> {
+    use crate::schema::{object, virtual_object_revision, virtual_object_revision_object};
+    let revisions = virtual_object_revision::table
+        .filter(virtual_object_revision::virtual_object_id.eq(vobj.id))
+        .order(virtual_object_revision::id.desc())
+        .load::<VirtualObjectRevision>(conn)
+        .map_err(|err| format!("{}", err))?;
+    let ids: Vec<i32> = revisions.iter().map(|revision| revision.id).collect();
+    let rows = virtual_object_revision_object::table
+        .inner_join(object::table)
+        .filter(virtual_object_revision_object::revision_id.eq_any(&ids))
+        .select((
+            virtual_object_revision_object::revision_id,
+            object::all_columns,
+        ))
+        .load::<(i32, Object)>(conn)
+        .map_err(|err| format!("{}", err))?;
+    let mut objects: HashMap<i32, Vec<Object>> = HashMap::new();
+    for (revision_id, object) in rows {
+        objects.entry(revision_id).or_default().push(object);
+    }
+    Ok(revisions
+        .into_iter()
+        .map(|revision| {
+            let objects = objects.remove(&revision.id).unwrap_or_default();
+            (revision, objects)
+        })
+        .collect())
+}
+
+// Restores the relations and primary object of an earlier revision,
+// which is then recorded as a revision of its own
+pub fn rollback_virtual_object(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+    revision_id: i32,
+    actor: Option<&str>,
+) -> Result<Option<i32>, String> {
+    let action = format!("rollback to {}", revision_id);
+    revise_virtual_object(conn, vobj, actor, &action, || {
+        restore_revision(conn, vobj, revision_id)
+    })
+}
+
+fn restore_revision(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+    revision_id: i32,
+) -> Result<(), String> {
+    use crate::schema::{virtual_object, virtual_object_relation, virtual_object_revision};
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let revision = virtual_object_revision::table
+            .filter(virtual_object_revision::id.eq(revision_id))
+            .filter(virtual_object_revision::virtual_object_id.eq(vobj.id))
+            .first::<VirtualObjectRevision>(conn)?;
+        let state = revision_state(conn, &revision)?;
+        diesel::delete(virtual_object_relation::table)
+            .filter(virtual_object_relation::virtual_object_id.eq(vobj.id))
+            .execute(conn)?;
+        let relations: Vec<ReplaceVirtualObjectRelation> = state
+            .object_ids
+            .iter()
+            .map(|object_id| ReplaceVirtualObjectRelation {
+                virtual_object_id: vobj.id,
+                object_id: *object_id,
+            })
+            .collect();
+        diesel::insert_into(virtual_object_relation::table)
+            .values(relations)
+            .execute(conn)?;
+        diesel::update(virtual_object::table)
+            .set(virtual_object::primary_object_id.eq(state.primary_object_id))
+            .filter(virtual_object::id.eq(vobj.id))
+            .execute(conn)?;
+        Ok(())
+    })
+    .map_err(|err| match err {
+        diesel::result::Error::NotFound => {
+            format!("{} has no revision {}", vobj.object_path, revision_id)
+        }
+        err => format!("{}", err),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revision_states_ignore_order() {
+        assert_eq!(
+            RevisionState::new(Some(1), vec![3, 1, 2, 3]),
+            RevisionState::new(Some(1), vec![1, 2, 3])
+        );
+        assert_ne!(
+            RevisionState::new(None, vec![1]),
+            RevisionState::new(Some(1), vec![1])
+        );
+        assert_eq!(revisions_past_retention(&[9, 8, 7, 6], 2), &[7, 6]);
+        assert!(revisions_past_retention(&[9, 8], 2).is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright (C) 2022 Cendyne.
+// This file is part of Cendyne Media-Server.
+
+// Cendyne Media-Server is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// Cendyne Media-Server is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use diesel::dsl::not;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use std::collections::HashMap;
+
+use crate::models::{NewVirtualObjectTag, VirtualObject};
+
+pub const MAX_TAG_LENGTH: usize = 64;
+pub const DEFAULT_TAG_PAGE: i64 = 50;
+pub const MAX_TAG_PAGE: i64 = 500;
+
+// Tags are compared exactly, so they are normalized to lower case
+pub fn parse_tag(input: &str) -> Result<String, String> {
+    let tag = input.trim().to_ascii_lowercase();
+    if tag.is_empty() || tag.len() > MAX_TAG_LENGTH {
+        return Err(format!(
+            "Tag \"{}\" must be between 1 and {} characters",
+            input, MAX_TAG_LENGTH
+        ));
+    }
+    if !tag
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+    {
+        return Err(format!("Tag \"{}\" has unsupported characters", input));
+    }
+    Ok(tag)
+}
+
+// Comma separated, as used in query parameters
+pub fn parse_tag_list(input: &str) -> Result<Vec<String>, String> {
+    input
+        .split(',')
+        .filter(|tag| !tag.trim().is_empty())
+        .map(parse_tag)
+        .collect()
+}
+
+pub fn find_tags_for_virtual_object(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+) -> Result<Vec<String>, String> {
+    use crate::schema::virtual_object_tag;
+    let result = virtual_object_tag::table
+        .select(virtual_object_tag::tag)
+        .filter(virtual_object_tag::virtual_object_id.eq(vobj.id))
+        .order(virtual_object_tag::tag.asc())
+        .load(conn)
+        .map_err(|err| format!("{}", err))?;
+    Ok(result)
+}
+
+pub fn find_tags_for_virtual_objects(
+    conn: &SqliteConnection,
+    ids: &[i32],
+) -> Result<HashMap<i32, Vec<String>>, String> {
+    use crate::schema::virtual_object_tag;
+    let rows: Vec<(i32, String)> = virtual_object_tag::table
+        .filter(virtual_object_tag::virtual_object_id.eq_any(ids))
+        .order(virtual_object_tag::tag.asc())
+        .load(conn)
+        .map_err(|err| format!("{}", err))?;
+    let mut result: HashMap<i32, Vec<String>> = HashMap::new();
+    for (id, tag) in rows {
+        result.entry(id).or_default().push(tag);
+    }
+    Ok(result)
+}
+
+// Each tag once, skipping those already present or also being removed
+fn tags_to_add(add: &[String], remove: &[String], existing: &[String]) -> Vec<String> {
+    let mut tags: Vec<String> = add
+        .iter()
+        .filter(|tag| !existing.contains(tag) && !remove.contains(tag))
+        .cloned()
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+pub fn update_virtual_object_tags(
+    conn: &SqliteConnection,
+    vobj: &VirtualObject,
+    add: &[String],
+    remove: &[String],
+) -> Result<(), String> {
+    use crate::schema::virtual_object_tag;
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let existing: Vec<String> = virtual_object_tag::table
+            .select(virtual_object_tag::tag)
+            .filter(virtual_object_tag::virtual_object_id.eq(vobj.id))
+            .load(conn)?;
+        let new_tags: Vec<NewVirtualObjectTag> = tags_to_add(add, remove, &existing)
+            .into_iter()
+            .map(|tag| NewVirtualObjectTag {
+                virtual_object_id: vobj.id,
+                tag,
+            })
+            .collect();
+        diesel::delete(virtual_object_tag::table)
+            .filter(virtual_object_tag::virtual_object_id.eq(vobj.id))
+            .filter(virtual_object_tag::tag.eq_any(remove))
+            .execute(conn)?;
+        diesel::insert_into(virtual_object_tag::table)
+            .values(&new_tags)
+            .execute(conn)?;
+        Ok(())
+    })
+    .map_err(|err| format!("{}", err))?;
+    println!(
+        "Tagged {} with {:?}, removed {:?}",
+        vobj.object_path, add, remove
+    );
+    Ok(())
+}
+
+// Virtual objects having all of `all`, at least one of `any` when given,
+// and none of `none`, ordered by id and starting after `after`
+pub fn find_virtual_objects_by_tags(
+    conn: &SqliteConnection,
+    all: &[String],
+    any: &[String],
+    none: &[String],
+    after: Option<i32>,
+    limit: i64,
+) -> Result<Vec<VirtualObject>, String> {
+    use crate::schema::virtual_object;
+    use crate::schema::virtual_object_tag;
+    if all.is_empty() && any.is_empty() {
+        return Err("At least one tag must be required".to_string());
+    }
+    let mut query = virtual_object::table.into_boxed();
+    for tag in all {
+        query = query.filter(
+            virtual_object::id.eq_any(
+                virtual_object_tag::table
+                    .select(virtual_object_tag::virtual_object_id)
+                    .filter(virtual_object_tag::tag.eq(tag.clone())),
+            ),
+        );
+    }
+    if !any.is_empty() {
+        query = query.filter(
+            virtual_object::id.eq_any(
+                virtual_object_tag::table
+                    .select(virtual_object_tag::virtual_object_id)
+                    .filter(virtual_object_tag::tag.eq_any(any.to_vec())),
+            ),
+        );
+    }
+    if !none.is_empty() {
+        query = query.filter(not(virtual_object::id.eq_any(
+            virtual_object_tag::table
+                .select(virtual_object_tag::virtual_object_id)
+                .filter(virtual_object_tag::tag.eq_any(none.to_vec())),
+        )));
+    }
+    if let Some(after) = after {
+        query = query.filter(virtual_object::id.gt(after));
+    }
+    let result = query
+        .order(virtual_object::id.asc())
+        .limit(limit)
+        .load(conn)
+        .map_err(|err| format!("{}", err))?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_normalized() {
+        assert_eq!(
+            Ok("campaign-2026".to_string()),
+            parse_tag(" Campaign-2026 ")
+        );
+        assert!(parse_tag("").is_err());
+        assert!(parse_tag("has space").is_err());
+        assert!(parse_tag(&"a".repeat(65)).is_err());
+        assert_eq!(
+            Ok(vec!["hero".to_string(), "ns:tag".to_string()]),
+            parse_tag_list("hero,,NS:tag")
+        );
+    }
+
+    #[test]
+    fn tags_are_added_once() {
+        let add = parse_tag_list("hero,Hero,HERO,banner,old").unwrap();
+        let remove = vec!["old".to_string()];
+        let existing = vec!["banner".to_string()];
+        assert_eq!(
+            vec!["hero".to_string()],
+            tags_to_add(&add, &remove, &existing)
+        );
+    }
+}